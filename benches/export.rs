@@ -0,0 +1,122 @@
+//! Throughput of a whole export cycle with large responses, served locally.
+//!
+//! The api caps every category at 100 entries, so the bench exports 100 channels to get
+//! 10k entries per category through the emit loops.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use metrics::KeyData;
+use reqwest::Url;
+use se_stats_exporter::{
+    export_stats,
+    sink::MetricSink,
+    state::ExportState,
+    stats_api::{ApiClient, MAX_ENTRIES},
+    ExportConfig, ExportName,
+};
+use serde_json::json;
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::runtime::Runtime;
+
+const CHANNELS: usize = 100;
+
+/// Drops every value, so only the cost of building them is measured.
+#[derive(Debug)]
+struct NullSink;
+
+impl MetricSink for NullSink {
+    fn gauge(&self, _key: &KeyData, _value: f64) {}
+
+    fn counter(&self, _key: &KeyData, _value: u64) {}
+}
+
+/// A `ChatStats` body with the most entries the api returns in every category.
+fn stats_body() -> String {
+    let entries = |key: &str| -> Vec<_> {
+        (0..MAX_ENTRIES)
+            .map(|i| json!({ key: format!("{}{}", key, i), "amount": 1000 + i }))
+            .collect()
+    };
+    let emotes = |provider: &str| -> Vec<_> {
+        (0..MAX_ENTRIES)
+            .map(|i| json!({"id": i.to_string(), "emote": format!("{}{}", provider, i), "amount": 1000 + i}))
+            .collect()
+    };
+
+    json!({
+        "channel": "bench",
+        "totalMessages": u32::MAX,
+        "chatters": entries("name"),
+        "hashtags": entries("hashtag"),
+        "commands": entries("command"),
+        "bttvEmotes": emotes("bttv"),
+        "ffzEmotes": emotes("ffz"),
+        "twitchEmotes": emotes("twitch"),
+    })
+    .to_string()
+}
+
+/// Answer every `chatstats/{channel}/stats` request with `body`.
+fn serve(runtime: &Runtime, body: String) -> Url {
+    let body: Arc<str> = body.into();
+    let _guard = runtime.enter();
+
+    let make_service = make_service_fn(move |_| {
+        let body = body.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let response = if request.uri().path().ends_with("/stats") {
+                    Response::new(Body::from(body.to_string()))
+                } else {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::NOT_FOUND;
+                    response
+                };
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+    runtime.spawn(server);
+
+    url
+}
+
+fn export_cycle(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let url = serve(&runtime, stats_body());
+
+    // after the first cycle every response comes from the cache, so localhost http does
+    // not drown out the time spent exporting
+    let client = ApiClient::new()
+        .unwrap()
+        .with_base_url(url)
+        .with_cache_ttl(Duration::from_secs(3600));
+    let config = ExportConfig::all()
+        .with_export(ExportName::Channel, false)
+        .with_export(ExportName::ChatEmotes, false)
+        .with_channels((0..CHANNELS).map(|i| format!("channel{}", i)).collect())
+        .with_fetch_concurrency(CHANNELS)
+        .with_limit(None);
+    let state = ExportState::with_sink(Arc::new(NullSink));
+
+    let mut group = c.benchmark_group("export_stats");
+    // chatters, hashtags, commands and three emote providers
+    group.throughput(Throughput::Elements((6 * MAX_ENTRIES * CHANNELS) as u64));
+    group.bench_function("100 channels", |b| {
+        b.iter(|| {
+            runtime
+                .block_on(export_stats(&config, &client, &state))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, export_cycle);
+criterion_main!(benches);
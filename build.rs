@@ -0,0 +1,30 @@
+use std::{env, process::Command};
+
+/// Run `program` and return its trimmed stdout, if it succeeded.
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.trim().to_string()).filter(|stdout| !stdout.is_empty())
+}
+
+fn main() {
+    // source tarballs and docker builds without .git get "unknown"
+    let git_sha = output("git", &["rev-parse", "--short", "HEAD"]);
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc_version = output(&rustc, &["--version"]);
+
+    println!(
+        "cargo:rustc-env=SE_STATS_GIT_SHA={}",
+        git_sha.as_deref().unwrap_or("unknown")
+    );
+    println!(
+        "cargo:rustc-env=SE_STATS_RUSTC_VERSION={}",
+        rustc_version.as_deref().unwrap_or("unknown")
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
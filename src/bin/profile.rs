@@ -1,9 +1,79 @@
-use se_stats_exporter::{export_stats, stats_api::ApiClient, ExportConfig};
+use clap::{value_t_or_exit, App, Arg};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use se_stats_exporter::{export_stats, state::ExportState, stats_api::ApiClient, ExportConfig};
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "dhat-heap")]
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
 
 #[tokio::main]
-async fn main() {
-    let client = ApiClient::new().unwrap();
+async fn main() -> Result<(), Box<dyn Error>> {
+    let matches = App::new("profile")
+        .about("Measure a single export cycle against the live api")
+        .arg(
+            Arg::with_name("iterations")
+                .long("iterations")
+                .short("n")
+                .help("How often export_stats gets called")
+                .default_value("1"),
+        )
+        .get_matches();
+    let iterations = value_t_or_exit!(matches.value_of("iterations"), u32).max(1);
+
+    #[cfg(feature = "dhat-heap")]
+    let _profiler = dhat::Profiler::new_heap();
+
+    // Without a recorder the metrics macros skip building labels entirely, which
+    // would hide most of the emit cost. This one is never rendered or served.
+    metrics::set_boxed_recorder(Box::new(PrometheusBuilder::new().build()))?;
+
+    let client = ApiClient::new()?;
     let config = ExportConfig::all();
+    let state = ExportState::new();
+
+    let mut elapsed = Duration::default();
+    #[cfg(feature = "dhat-heap")]
+    let (mut blocks, mut bytes) = (0, 0);
+
+    for _ in 0..iterations {
+        #[cfg(feature = "dhat-heap")]
+        let before = dhat::HeapStats::get();
+        let start = Instant::now();
+
+        export_stats(&config, &client, &state).await?;
+
+        elapsed += start.elapsed();
+        #[cfg(feature = "dhat-heap")]
+        {
+            let after = dhat::HeapStats::get();
+            blocks += after.total_blocks - before.total_blocks;
+            bytes += after.total_bytes - before.total_bytes;
+        }
+    }
+
+    println!("iterations:   {}", iterations);
+    println!(
+        "wall time:    {:?} total, {:?} avg",
+        elapsed,
+        elapsed / iterations
+    );
+    #[cfg(feature = "dhat-heap")]
+    {
+        println!(
+            "allocations:  {} total, {} avg",
+            blocks,
+            blocks / u64::from(iterations)
+        );
+        println!(
+            "bytes:        {} total, {} avg",
+            bytes,
+            bytes / u64::from(iterations)
+        );
+    }
 
-    export_stats(&config, &client).await;
+    Ok(())
 }
@@ -1,71 +1,1256 @@
-use clap::{value_t_or_exit, values_t_or_exit, App, Arg, ArgMatches};
-use metrics::register_gauge;
-use metrics_exporter_prometheus::PrometheusBuilder;
-use se_stats_exporter::{export_stats, stats_api::ApiClient, ExportConfig, ExportName};
-use std::{error::Error, net::SocketAddr, time::Duration};
-use tokio::time;
+use clap::{arg_enum, value_t, values_t, App, Arg, ArgMatches, SubCommand};
+use metrics::SetRecorderError;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusRecorder};
+use metrics_util::MetricKindMask;
+use reqwest::Proxy;
+use se_stats_exporter::{
+    dry_run::{CollectingRecorder, LoggingRecorder},
+    emit_data_age, export_stats_guarded,
+    exposition::{build_recorder, ExpiringRecorder, MetricsHandle},
+    filter::{self, FilterError, Filters},
+    health::{self, Health},
+    labels::{LabelKeyError, LabelKeys},
+    names::{
+        is_valid_prefix, register_metrics, set_build_info, AmountKind, MetricSeparator,
+        DEFAULT_PREFIX,
+    },
+    otlp::{OtlpExporter, OtlpRecorder},
+    push::PushGateway,
+    run_ws_export,
+    sanitize::Sanitize,
+    schedule::JitteredInterval,
+    settings::{
+        is_valid_request_timeout, is_valid_smoothing, Interval, Jitter, Settings, SettingsError,
+    },
+    state::{ExportState, FailurePolicy},
+    stats_api::{self, ApiClient, Channel, ChatStats, StatsWindow},
+    stats_ws::{self, WsClient},
+    statsd::StatsdRecorder,
+    textfile, CycleError, ExportConfig, ExportName,
+};
+use serde::Serialize;
+use std::{
+    borrow::Cow, error::Error, fmt::Display, future, io, net::SocketAddr, path::Path, process,
+    str::FromStr, sync::Arc, time::Duration,
+};
+use tokio::{signal, sync::oneshot, task::JoinError, time};
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
+
+/// The `--export` value that turns every export off.
+const NO_EXPORTS: &str = "none";
+
+/// How often the data age gauges are refreshed, independent of the export interval.
+const DATA_AGE_INTERVAL: Duration = Duration::from_secs(1);
+
+// exit codes, as listed in `--help`
+const EXIT_FAILURE: i32 = 1;
+const EXIT_INVALID: i32 = 2;
+const EXIT_SOCKET: i32 = 3;
+const EXIT_API: i32 = 4;
+
+arg_enum! {
+    #[derive(PartialEq, Debug, Clone, Copy)]
+    enum Backend {
+        Prometheus,
+        Statsd,
+        Otlp,
+    }
+}
 
 fn get_matches() -> ArgMatches<'static> {
     App::new("se-stats-exporter")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help("Read settings from a toml file")
+                .long_help(
+                    "Read settings from a toml file. Flags given on the command line \
+                     override values from the file",
+                )
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("export")
                 .long("export")
                 .short("e")
                 .help("Set what gets exported")
+                .long_help(
+                    "Set what gets exported, as a comma separated list. `none` exports \
+                     nothing, e.g. to only test the metrics endpoint",
+                )
                 .takes_value(true)
-                .possible_values(&ExportName::variants())
+                .possible_values(&export_tokens())
                 .use_delimiter(true)
                 .default_value(
                     option_env!("SESTATS_EXPORT").unwrap_or("bttv,ffz,twitch,channel,chatter"),
                 )
                 .case_insensitive(true),
         )
+        .arg(
+            Arg::with_name("channel")
+                .long("channel")
+                .short("c")
+                .help("Set the channels to export stats for")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .default_value(option_env!("SESTATS_CHANNEL").unwrap_or("global")),
+        )
+        .arg(
+            Arg::with_name("fetch-concurrency")
+                .long("fetch-concurrency")
+                .help("Fetch the stats of at most this many channels at once")
+                .default_value(option_env!("SESTATS_FETCH_CONCURRENCY").unwrap_or("4")),
+        )
+        .arg(
+            Arg::with_name("limit")
+                .long("limit")
+                .help("Export at most this many chatters, hashtags, commands and emotes of each kind")
+                .long_help(
+                    "Export at most this many chatters, hashtags, commands and emotes of each \
+                     kind. --live counts the first this many entries it sees per channel",
+                )
+                .default_value(option_env!("SESTATS_LIMIT").unwrap_or("100")),
+        )
+        .arg(
+            Arg::with_name("top-channels")
+                .long("top-channels")
+                .help("Export at most this many top channels")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("address")
                 .long("address")
                 .short("a")
                 .help("Set the address for the prometheus scrape endpoint")
+                .long_help(
+                    "Set the address for the prometheus scrape endpoint. Use 0.0.0.0:9001 \
+                     to listen on every interface, e.g. inside a container. The endpoint \
+                     has no authentication, so everyone who can reach it can read the metrics",
+                )
                 .default_value(option_env!("SESTATS_ADDRESS").unwrap_or("127.0.0.1:9001")),
         )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .help("Set where the metrics go")
+                .long_help(
+                    "Set where the metrics go: `prometheus` serves them on --address, \
+                     `statsd` sends them to --statsd-address, `otlp` pushes them to \
+                     --otlp-endpoint after every export",
+                )
+                .takes_value(true)
+                .possible_values(&Backend::variants())
+                .default_value("prometheus")
+                .case_insensitive(true),
+        )
+        .arg(
+            Arg::with_name("statsd-address")
+                .long("statsd-address")
+                .help("Set the address of the statsd server")
+                .default_value(option_env!("SESTATS_STATSD_ADDRESS").unwrap_or("127.0.0.1:8125")),
+        )
+        .arg(
+            Arg::with_name("otlp-endpoint")
+                .long("otlp-endpoint")
+                .help("Set the OTLP/HTTP collector the otlp backend pushes to")
+                .default_value(
+                    option_env!("SESTATS_OTLP_ENDPOINT").unwrap_or("http://localhost:4318"),
+                ),
+        )
+        .arg(
+            Arg::with_name("health-address")
+                .long("health-address")
+                .help("Serve /healthz and /readyz on this address")
+                .long_help(
+                    "Serve /healthz and /readyz on this address. /healthz answers 200 when \
+                     the last export succeeded and 503 otherwise, /readyz 200 when the api \
+                     was reachable at its last check, which is at most 30s old",
+                )
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("interval")
                 .long("interval")
                 .short("i")
-                .help("Export interval in seconds")
-                .long_help("How often the scape endpoint should get updated")
-                .default_value(option_env!("SESTATS_INTERVAL").unwrap_or("10")),
+                .help("Export interval, e.g. 30s, 5m or a number of seconds")
+                .long_help(
+                    "How often the scape endpoint should get updated, e.g. 30s, 5m or \
+                     1h30m. A bare number counts as seconds. At least 1s",
+                )
+                .default_value(option_env!("SESTATS_INTERVAL").unwrap_or("10"))
+                .validator(|v| v.parse::<Interval>().map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("interval-jitter")
+                .long("interval-jitter")
+                .help("Move every export randomly by up to this much, e.g. 2s")
+                .long_help(
+                    "Move every export randomly by up to this much in both directions, \
+                     e.g. 2s or 500ms, so instances that started together do not poll the \
+                     api at the same time. Capped at the interval",
+                )
+                .default_value("0")
+                .validator(|v| v.parse::<Jitter>().map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("request-timeout")
+                .long("request-timeout")
+                .help("Timeout for api requests in seconds")
+                .default_value(option_env!("SESTATS_REQUEST_TIMEOUT").unwrap_or("30"))
+                .validator(|v| match v.parse::<u64>() {
+                    Ok(seconds) if is_valid_request_timeout(seconds) => Ok(()),
+                    _ => Err(String::from("expected a whole number of seconds, at least 1")),
+                }),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .long("proxy")
+                .help("Send api requests through this http proxy")
+                .long_help(
+                    "Send api requests through this http proxy, e.g. http://proxy:3128. \
+                     Without it HTTP_PROXY, HTTPS_PROXY and NO_PROXY are honored. The \
+                     websocket feed of --live never uses a proxy",
+                )
+                .takes_value(true)
+                .validator(|v| Proxy::all(&v).map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .help("Send this bearer token with every api request")
+                .long_help(
+                    "Send this bearer token as Authorization header with every api request. \
+                     The public chatstats endpoints work without one. It is never logged",
+                )
+                .takes_value(true)
+                .env("SESTATS_TOKEN")
+                .hide_env_values(true),
+        )
+        .arg(
+            Arg::with_name("cache-ttl")
+                .long("cache-ttl")
+                .help("Reuse fetched channel stats for this many seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metric-prefix")
+                .long("metric-prefix")
+                .help("Set the prefix of every metric name")
+                .takes_value(true)
+                .default_value(DEFAULT_PREFIX)
+                .validator(|v| {
+                    if is_valid_prefix(&v) {
+                        Ok(())
+                    } else {
+                        Err(String::from(
+                            "expected letters, digits and underscores, not starting with a digit or `__`",
+                        ))
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("metric-separator")
+                .long("metric-separator")
+                .help("Set the separator used in metric names")
+                .takes_value(true)
+                .possible_values(&MetricSeparator::variants())
+                .default_value("dot")
+                .case_insensitive(true),
+        )
+        .arg(
+            Arg::with_name("amounts")
+                .long("amounts")
+                .help("Export usage amounts as gauges, counters or deltas")
+                .long_help(
+                    "Export the amounts of emotes, chatters, hashtags and commands as \
+                     `gauge`s or `counter`s. Counters make rate() work, but entries that \
+                     drop out of the top list keep their last value and grow by their whole \
+                     total when they come back. `delta` exports the \
+                     growth since the last export as e.g. sestats_emote_delta instead",
+                )
+                .takes_value(true)
+                .possible_values(&AmountKind::variants())
+                .default_value("gauge")
+                .case_insensitive(true),
+        )
+        .arg(
+            Arg::with_name("window")
+                .long("window")
+                .help("Export the channel stats of the last 7 or 30 days instead of all time")
+                .long_help(
+                    "Export the channel stats of `last7d` or `last30d` instead of `alltime`. \
+                     Anything but alltime adds a `window` label to those series",
+                )
+                .takes_value(true)
+                .possible_values(&StatsWindow::variants())
+                .default_value("alltime")
+                .case_insensitive(true),
+        )
+        .arg(
+            Arg::with_name("sanitize")
+                .long("sanitize")
+                .help("Clean up chatter, emote, hashtag and command names")
+                .long_help(
+                    "Clean up chatter, emote, hashtag and command names before exporting \
+                     them: `strip` trims them and removes control characters, `lowercase` \
+                     also lowercases them. Entries that end up with the same name are \
+                     exported as one with their amounts summed",
+                )
+                .takes_value(true)
+                .possible_values(&Sanitize::variants())
+                .default_value("off")
+                .case_insensitive(true),
+        )
+        .arg(
+            Arg::with_name("on-failure")
+                .long("on-failure")
+                .help("Set what happens to exported series when a fetch fails")
+                .long_help(
+                    "Set what happens to exported series when a fetch fails: \
+                     `hold` keeps the last values, `zero` sets them to 0 and \
+                     `expire` removes them after two missed intervals",
+                )
+                .takes_value(true)
+                .possible_values(&FailurePolicy::variants())
+                .default_value("hold")
+                .case_insensitive(true),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Fetch once and print the metrics instead of serving them"),
+        )
+        .arg(
+            Arg::with_name("dry-run-format")
+                .long("dry-run-format")
+                .help("Print the metrics of a dry run as a table or log every update")
+                .long_help(
+                    "Print the metrics of a dry run as a table on stdout once the export \
+                     is done, or log every update at info level while it runs. Defaults \
+                     to table",
+                )
+                .takes_value(true)
+                .possible_values(&["table", "log"])
+                // a default value would count as present and always require --dry-run
+                .requires("dry-run"),
+        )
+        .arg(
+            Arg::with_name("once")
+                .long("once")
+                .help("Export once and exit without serving the metrics")
+                .long_help(
+                    "Export once and exit without serving the metrics. The exit status \
+                     is non-zero if any fetch failed",
+                ),
+        )
+        .arg(
+            Arg::with_name("push-gateway")
+                .long("push-gateway")
+                .help("Push the metrics to a pushgateway after every export")
+                .long_help(
+                    "Push the metrics to the pushgateway at this url after every export, \
+                     e.g. http://localhost:9091. Combine with --once to run from cron",
+                )
+                .takes_value(true)
+                .conflicts_with("dry-run"),
+        )
+        .arg(
+            Arg::with_name("textfile")
+                .long("textfile")
+                .help("Write the metrics to this file after every export instead of serving them")
+                .long_help(
+                    "Write the metrics to this file after every export instead of serving \
+                     them, e.g. for the textfile collector of node_exporter. The file is \
+                     replaced atomically",
+                )
+                .takes_value(true)
+                .conflicts_with("dry-run"),
+        )
+        .arg(
+            Arg::with_name("live")
+                .long("live")
+                .help("Also count changes from the websocket feed between polls")
+                .long_help(
+                    "Also count changes from the websocket feed between polls. Watches the \
+                     rooms of --channel, which is `global` by default",
+                )
+                .conflicts_with_all(&["dry-run", "once"]),
+        )
+        .arg(
+            Arg::with_name("emote-ids")
+                .long("emote-ids")
+                .help("Add the emote id as a label to emote metrics")
+                .long_help(
+                    "Add the emote id as an `emote_id` label to emote metrics, e.g. to \
+                     link to emote images. Raises cardinality when emotes get renamed",
+                ),
+        )
+        .arg(
+            Arg::with_name("last-message")
+                .long("last-message")
+                .help("Export when each channel's user last wrote in chat")
+                .long_help(
+                    "Export when each channel's user last wrote in chat as \
+                     sestats_user_last_message_timestamp_seconds. Only works together \
+                     with --export chatemotes",
+                ),
+        )
+        .arg(
+            Arg::with_name("smoothing")
+                .long("smoothing")
+                .help("Smooth usage amount gauges with this EMA alpha")
+                .long_help(
+                    "Export an exponential moving average of the emote, chatter, hashtag \
+                     and command amounts instead of the raw amounts. The alpha is the \
+                     weight of the newest amount, between 0 and 1, e.g. 0.3. Has no effect \
+                     with --amounts counter",
+                )
+                .takes_value(true)
+                .validator(|v| match v.parse::<f64>() {
+                    Ok(alpha) if is_valid_smoothing(alpha) => Ok(()),
+                    _ => Err(String::from("expected a number above 0 and at most 1")),
+                }),
+        )
+        .arg(
+            Arg::with_name("allow")
+                .long("allow")
+                .help("Only export entries of a metric that match a pattern")
+                .long_help(
+                    "Only export the chatters, hashtags, commands, emotes or top channels \
+                     that match one of the allowed patterns, e.g. `chatter=forsen` or \
+                     `emote=/^pepe/`. Patterns in slashes are regexes, anything else has \
+                     to match the whole name",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_filter),
+        )
+        .arg(
+            Arg::with_name("deny")
+                .long("deny")
+                .help("Skip entries of a metric that match a pattern")
+                .long_help(
+                    "Skip the chatters, hashtags, commands, emotes or top channels that \
+                     match the pattern, e.g. `chatter=/bot$/`. Denied entries are skipped \
+                     even if they are allowed",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_filter),
+        )
+        .arg(
+            Arg::with_name("allow-file")
+                .long("allow-file")
+                .help("Like --allow, with one pattern per line read from a file")
+                .long_help(
+                    "Like --allow, e.g. `chatter=allowed.txt` with one pattern per line. \
+                     Empty lines and lines starting with # are skipped",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_filter),
+        )
+        .arg(
+            Arg::with_name("deny-file")
+                .long("deny-file")
+                .help("Like --deny, with one pattern per line read from a file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_filter),
+        )
+        .arg(
+            Arg::with_name("label-key")
+                .long("label-key")
+                .help("Override the label key of a metric")
+                .long_help(
+                    "Override the label key used for the entry name of a metric, \
+                     e.g. `chatter=user` or `emote=emote_name`",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .validator(|v| {
+                    if v.contains('=') {
+                        Ok(())
+                    } else {
+                        Err(String::from("expected <metric>=<label key>"))
+                    }
+                }),
         )
-        .get_matches()
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Print the raw stats of the configured channels as json and exit"),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Print every export with the metrics and labels it produces and exit"),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check a config file and print it normalized, without connecting anywhere")
+                .arg(
+                    Arg::with_name("config")
+                        .long("config")
+                        .help("The toml file to check")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .after_help(
+            "EXIT CODES:\n    \
+             1    Anything not listed below\n    \
+             2    Invalid flags or config file\n    \
+             3    A socket could not be opened, e.g. the metrics address is in use\n    \
+             4    The api could not be reached or gave an unusable answer",
+        )
+        .get_matches_safe()
+        .unwrap_or_else(usage_error)
+}
+
+/// Print a clap error like clap does, but exit with the code for invalid flags.
+fn usage_error<T>(e: clap::Error) -> T {
+    // help and version are errors too, they go to stdout and exit with 0
+    if !e.use_stderr() {
+        e.exit()
+    }
+    eprintln!("{}", e.message);
+    process::exit(EXIT_INVALID)
+}
+
+/// Use the value given on the command line, then the one from the config file and
+/// then the flag's default.
+fn pick<T>(matches: &ArgMatches, name: &str, from_file: Option<T>) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match from_file {
+        Some(value) if matches.occurrences_of(name) == 0 => value,
+        _ => value_t!(matches.value_of(name), T).unwrap_or_else(usage_error),
+    }
+}
+
+/// Every value `--export` accepts.
+fn export_tokens() -> Vec<&'static str> {
+    let mut tokens = ExportName::variants().to_vec();
+    tokens.push(NO_EXPORTS);
+    tokens
+}
+
+/// Read `--export`, where `none` stands for an empty list.
+fn parse_exports(matches: &ArgMatches) -> Result<Vec<ExportName>, clap::Error> {
+    let values: Vec<&str> = matches.values_of("export").unwrap_or_default().collect();
+
+    if values
+        .iter()
+        .any(|value| value.eq_ignore_ascii_case(NO_EXPORTS))
+    {
+        if values.len() > 1 {
+            return Err(clap::Error::with_description(
+                "`none` cannot be combined with other exports",
+                clap::ErrorKind::ArgumentConflict,
+            ));
+        }
+        return Ok(Vec::new());
+    }
+
+    values_t!(matches.values_of("export"), ExportName)
+}
+
+fn validate_filter(value: String) -> Result<(), String> {
+    if value.contains('=') {
+        Ok(())
+    } else {
+        Err(String::from("expected <metric>=<pattern>"))
+    }
+}
+
+/// Collect the allow and deny lists from the config file, the flags and the pattern files.
+fn read_filters(matches: &ArgMatches, settings: &Settings) -> Result<Filters, FilterError> {
+    let flag = |name| {
+        matches
+            .values_of(name)
+            .into_iter()
+            .flatten()
+            .filter_map(|v: &str| v.split_once('='))
+    };
+    let mut filters = Filters::default();
+
+    for (metric, patterns) in &settings.allow {
+        for pattern in patterns {
+            filters.allow(metric, pattern)?;
+        }
+    }
+    for (metric, patterns) in &settings.deny {
+        for pattern in patterns {
+            filters.deny(metric, pattern)?;
+        }
+    }
+    for (metric, pattern) in flag("allow") {
+        filters.allow(metric, pattern)?;
+    }
+    for (metric, pattern) in flag("deny") {
+        filters.deny(metric, pattern)?;
+    }
+    for (metric, path) in flag("allow-file") {
+        for pattern in filter::read_patterns(Path::new(path))? {
+            filters.allow(metric, &pattern)?;
+        }
+    }
+    for (metric, path) in flag("deny-file") {
+        for pattern in filter::read_patterns(Path::new(path))? {
+            filters.deny(metric, &pattern)?;
+        }
+    }
+
+    Ok(filters)
+}
+
+/// Parse and check the config file at `path`, then print it normalized.
+fn validate_config(path: &Path) -> Result<(), AppError> {
+    let settings = Settings::from_file(path)?;
+    settings.validate()?;
+    let normalized = toml::to_string(&settings).map_err(|source| AppError::Normalize {
+        path: path.display().to_string(),
+        source,
+    })?;
+
+    eprintln!("{} is valid", path.display());
+    print!("{}", normalized);
+    Ok(())
+}
+
+/// Like [`pick`] for flags that take several values.
+fn pick_many<T>(matches: &ArgMatches, name: &str, from_file: Option<Vec<T>>) -> Vec<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match from_file {
+        Some(values) if matches.occurrences_of(name) == 0 => values,
+        _ => values_t!(matches.values_of(name), T).unwrap_or_else(usage_error),
+    }
+}
+
+fn main() {
+    // match clap's error output instead of printing the debug representation
+    if let Err(e) = run() {
+        // some errors already include their cause in the message
+        let mut message = e.to_string();
+        let mut source = e.source();
+        while let Some(cause) = source {
+            let text = cause.to_string();
+            if !message.contains(&text) {
+                message = format!("{}: {}", message, text);
+            }
+            source = cause.source();
+        }
+        eprintln!("error: {}", message);
+        process::exit(e.exit_code());
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn run() -> Result<(), AppError> {
     let matches = get_matches();
 
-    let export_config: ExportConfig =
-        values_t_or_exit!(matches.values_of("export"), ExportName).into();
-    let listen_addess = value_t_or_exit!(matches.value_of("address"), SocketAddr);
-    let export_interval = value_t_or_exit!(matches.value_of("interval"), u64);
+    if let Some(validate) = matches.subcommand_matches("validate") {
+        return validate_config(Path::new(validate.value_of("config").unwrap()));
+    }
+
+    let settings = match matches.value_of("config") {
+        Some(path) => {
+            let settings = Settings::from_file(Path::new(path))?;
+            settings.validate()?;
+            settings
+        }
+        None => Settings::default(),
+    };
+
+    let label_keys = LabelKeys::from_overrides(
+        settings
+            .label_keys
+            .iter()
+            .map(|(metric, key)| (metric.as_str(), key.as_str()))
+            .chain(
+                matches
+                    .values_of("label-key")
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|v| v.split_once('=')),
+            ),
+    )?;
+    let filters = read_filters(&matches, &settings)?;
+    let prefix: String = pick(&matches, "metric-prefix", settings.prefix);
+    if !is_valid_prefix(&prefix) {
+        return Err(AppError::Usage(format!(
+            "{:?} is not a valid metric name prefix",
+            prefix
+        )));
+    }
+    let exports = match settings.export {
+        Some(exports) if matches.occurrences_of("export") == 0 => exports,
+        _ => parse_exports(&matches).unwrap_or_else(usage_error),
+    };
+    let export_config = ExportConfig::from(exports)
+        .with_channels(pick_many(&matches, "channel", settings.channels))
+        .with_fetch_concurrency(pick(
+            &matches,
+            "fetch-concurrency",
+            settings.fetch_concurrency,
+        ))
+        .with_limit(Some(pick(&matches, "limit", settings.limit)))
+        .with_top_channels_limit(if matches.is_present("top-channels") {
+            Some(value_t!(matches.value_of("top-channels"), usize).unwrap_or_else(usage_error))
+        } else {
+            settings.top_channels
+        })
+        .with_emote_ids(matches.is_present("emote-ids") || settings.emote_ids.unwrap_or(false))
+        .with_last_message(
+            matches.is_present("last-message") || settings.last_message.unwrap_or(false),
+        )
+        .with_smoothing(if matches.is_present("smoothing") {
+            Some(value_t!(matches.value_of("smoothing"), f64).unwrap_or_else(usage_error))
+        } else {
+            settings.smoothing
+        })
+        .with_label_keys(label_keys)
+        .with_prefix(prefix)
+        .with_separator(pick(&matches, "metric-separator", settings.separator))
+        .with_failure_policy(pick(&matches, "on-failure", settings.on_failure))
+        .with_amounts(pick(&matches, "amounts", settings.amounts))
+        .with_window(pick(&matches, "window", settings.window))
+        .with_sanitize(pick(&matches, "sanitize", settings.sanitize))
+        .with_filters(filters);
+    let listen_addess: SocketAddr = pick(&matches, "address", settings.address);
+    let export_interval = pick::<Interval>(&matches, "interval", settings.interval).duration();
+    let interval_jitter =
+        pick::<Jitter>(&matches, "interval-jitter", settings.interval_jitter).duration();
+    let request_timeout =
+        Duration::from_secs(pick(&matches, "request-timeout", settings.request_timeout));
+    let proxy = matches
+        .value_of("proxy")
+        .map(|proxy| {
+            Proxy::all(proxy).map_err(|source| AppError::InvalidProxy {
+                proxy: proxy.to_string(),
+                source,
+            })
+        })
+        .transpose()?;
+    let token = matches.value_of("token").map(String::from);
+    let cache_ttl = if matches.is_present("cache-ttl") {
+        Some(value_t!(matches.value_of("cache-ttl"), u64).unwrap_or_else(usage_error))
+    } else {
+        settings.cache_ttl
+    }
+    .map(Duration::from_secs);
+
+    let dry_run = matches.is_present("dry-run");
+    let once = matches.is_present("once");
+    let live = matches.is_present("live");
+    let dump = matches.subcommand_matches("dump").is_some();
+    let list = matches.subcommand_matches("list").is_some();
+    let backend = value_t!(matches.value_of("backend"), Backend).unwrap_or_else(usage_error);
+    if backend != Backend::Prometheus && matches.is_present("push-gateway") {
+        return Err(AppError::Usage(String::from(
+            "--push-gateway needs the prometheus backend",
+        )));
+    }
+    if backend != Backend::Prometheus && matches.is_present("textfile") {
+        return Err(AppError::Usage(String::from(
+            "--textfile needs the prometheus backend",
+        )));
+    }
+    let textfile = matches.value_of("textfile").map(Path::new);
+
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        // keep stdout clean for the dry run table and the dump
+        .with_writer(move || -> Box<dyn io::Write> {
+            if dry_run || dump {
+                Box::new(io::stderr())
+            } else {
+                Box::new(io::stdout())
+            }
+        })
+        .init();
+
+    if list {
+        list_exports(&export_config, backend);
+        return Ok(());
+    }
+
+    if dump {
+        let mut client = ApiClient::with_timeout(request_timeout)?;
+        if let Some(proxy) = proxy {
+            client = client.with_proxy(proxy)?;
+        }
+        if let Some(token) = token {
+            client = client.with_token(token);
+        }
+        return dump_stats(&client, export_config.channels()).await;
+    }
+
+    let mut otlp = None;
+    let (collecting_recorder, prometheus) = if dry_run
+        && matches.value_of("dry-run-format") == Some("log")
+    {
+        metrics::set_boxed_recorder(Box::new(LoggingRecorder::new()))?;
+        (None, None)
+    } else if dry_run {
+        let recorder: &'static CollectingRecorder = Box::leak(Box::new(CollectingRecorder::new()));
+        metrics::set_recorder(recorder)?;
+        (Some(recorder), None)
+    } else if backend == Backend::Statsd {
+        let address = matches.value_of("statsd-address").unwrap();
+        let recorder = StatsdRecorder::new(address).map_err(AppError::Statsd)?;
+        metrics::set_boxed_recorder(Box::new(recorder))?;
+        (None, None)
+    } else if backend == Backend::Otlp {
+        let recorder: &'static OtlpRecorder = Box::leak(Box::new(OtlpRecorder::new()));
+        metrics::set_recorder(recorder)?;
+        let exporter =
+            OtlpExporter::new(matches.value_of("otlp-endpoint").unwrap(), request_timeout)
+                .map_err(AppError::Client)?;
+        otlp = Some((exporter, recorder));
+        (None, None)
+    } else if once || textfile.is_some() {
+        // nothing will scrape us, so don't listen
+        let (recorder, _) = build_recorder(&export_config, export_interval, interval_jitter);
+        metrics::set_boxed_recorder(Box::new(recorder.clone()))?;
+        (None, Some(recorder))
+    } else {
+        let idle_timeout = export_config
+            .failure_policy()
+            .idle_timeout(export_interval, interval_jitter);
+        let builder = PrometheusBuilder::new().idle_timeout(MetricKindMask::GAUGE, idle_timeout);
+        let (recorder, exporter) = bind_exporter(builder, listen_addess)?;
+        let recorder = ExpiringRecorder::new(recorder, idle_timeout);
+        metrics::set_boxed_recorder(Box::new(recorder.clone()))?;
+        tokio::spawn(async move {
+            if let Err(e) = exporter.await {
+                error!("Metrics endpoint stopped: {}", e);
+            }
+        });
+        (None, Some(recorder))
+    };
+    let push_gateway = match matches.value_of("push-gateway") {
+        Some(url) => Some(PushGateway::new(url, request_timeout).map_err(AppError::Client)?),
+        None => None,
+    };
+
+    info!(
+        address = %listen_addess,
+        backend = %backend,
+        interval = export_interval.as_secs(),
+        interval_jitter = %humantime::format_duration(interval_jitter),
+        request_timeout = request_timeout.as_secs(),
+        cache_ttl = cache_ttl.map(|ttl| ttl.as_secs()),
+        authenticated = token.is_some(),
+        exports = %export_config
+            .enabled()
+            .iter()
+            .map(ExportName::to_string)
+            .collect::<Vec<_>>()
+            .join(","),
+        channels = %export_config.channels().join(","),
+        fetch_concurrency = export_config.fetch_concurrency(),
+        limit = export_config.limit(),
+        top_channels = export_config.top_channels_limit(),
+        prefix = export_config.prefix(),
+        separator = %export_config.separator(),
+        mode = if dry_run {
+            "dry-run"
+        } else if once {
+            "once"
+        } else if live {
+            "live"
+        } else {
+            "poll"
+        },
+        on_failure = %export_config.failure_policy(),
+        amounts = %export_config.amounts(),
+        window = %export_config.window(),
+        sanitize = %export_config.sanitize(),
+        smoothing = export_config.smoothing(),
+        push_gateway = push_gateway.as_ref().map(PushGateway::url),
+        otlp_endpoint = otlp.as_ref().map(|(exporter, _)| exporter.url()),
+        textfile = textfile.map(|path| path.display().to_string()).as_deref(),
+        "Starting se-stats-exporter"
+    );
+
+    register_metrics(
+        export_config.prefix(),
+        export_config.separator(),
+        export_config.amounts(),
+    );
+    set_build_info(export_config.prefix(), export_config.separator());
+
+    let mut client = ApiClient::with_timeout(request_timeout)?
+        .with_prefix(export_config.prefix().to_string())
+        .with_separator(export_config.separator());
+    if let Some(proxy) = proxy {
+        client = client.with_proxy(proxy)?;
+    }
+    if let Some(token) = token {
+        client = client.with_token(token);
+    }
+    if let Some(ttl) = cache_ttl {
+        client = client.with_cache_ttl(ttl);
+    }
+    // waiting out a rate limit past the next tick would only delay that cycle too
+    client = client.with_max_retry_delay(export_interval);
+    // series the state removes expire, everything else is refreshed
+    let state = Arc::new(match &prometheus {
+        Some(recorder) => ExportState::with_sink(Arc::new(recorder.clone())),
+        None => ExportState::new(),
+    });
+    let prometheus = prometheus.map(|recorder| {
+        if let Some(idle_timeout) = recorder.idle_timeout() {
+            let recorder = recorder.clone();
+            tokio::spawn(async move {
+                let mut interval = time::interval(idle_timeout / 2);
+                loop {
+                    interval.tick().await;
+                    recorder.refresh();
+                }
+            });
+        }
+        recorder.handle()
+    });
+
+    if dry_run {
+        let result = export_stats_guarded(&export_config, &client, &state).await;
+        if let Some(recorder) = collecting_recorder {
+            print!("{}", recorder.render_table());
+        }
+        return Ok(result?);
+    }
+
+    if once {
+        let result = export_stats_guarded(&export_config, &client, &state).await;
+        // write and push partial results too, Up and LastSuccess tell what failed
+        write_textfile(textfile, prometheus.as_ref()).map_err(AppError::Textfile)?;
+        push_metrics(push_gateway.as_ref(), prometheus.as_ref())
+            .await
+            .map_err(AppError::Push)?;
+        push_otlp(otlp.as_ref()).await.map_err(AppError::Push)?;
+        return Ok(result?);
+    }
+
+    let live_feed = if live {
+        let config = export_config.clone();
+        let api = client.clone();
+        let state = Arc::clone(&state);
+        let (stop, stopped) = oneshot::channel::<()>();
+        // fail at startup, a feed that drops later is only logged
+        let mut client = WsClient::new()
+            .await
+            .map_err(|e| AppError::Ws(Box::new(e)))?;
 
-    PrometheusBuilder::new()
-        .listen_address(listen_addess)
-        .install()?;
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                result = run_ws_export(&config, &api, &state, &mut client, config.channels()) => {
+                    if let Err(e) = result {
+                        error!("Live feed stopped: {}", e);
+                    }
+                }
+                _ = stopped => {}
+            }
 
-    tracing_subscriber::fmt::init();
+            if let Err(e) = client.close().await {
+                error!("Could not close live feed: {}", e);
+            }
+        });
 
-    register_gauge!("sestats.emote", "top emotes");
-    register_gauge!("sestats.total-messages", "total messages on twitch");
-    register_gauge!("sestats.chatter", "top chatters");
-    register_gauge!("sestats.channel", "top channels");
-    register_gauge!("sestats.command", "top commands");
-    register_gauge!("sestats.hashtag", "top hashtags");
+        Some((stop, handle))
+    } else {
+        None
+    };
 
-    let client = ApiClient::new()?;
+    let data_age = {
+        let config = export_config.clone();
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut interval = time::interval(DATA_AGE_INTERVAL);
+            loop {
+                interval.tick().await;
+                emit_data_age(&config, &state);
+            }
+        })
+    };
 
-    let mut interval = time::interval(Duration::from_secs(export_interval));
+    let health = Health::new();
+    if matches.is_present("health-address") {
+        let address =
+            value_t!(matches.value_of("health-address"), SocketAddr).unwrap_or_else(usage_error);
+        let health = health.clone();
+        // a probe should answer quickly, it is repeated anyway
+        let client = client.clone().with_retries(0, Duration::ZERO);
+        tokio::spawn(async move {
+            if let Err(e) = health::serve(address, health, client).await {
+                error!("Health endpoint stopped: {}", e);
+            }
+        });
+    }
+
+    let mut interval = JitteredInterval::new(export_interval, interval_jitter);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     loop {
-        interval.tick().await;
-        export_stats(&export_config, &client).await;
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = &mut shutdown => break,
+        }
+
+        // not part of the select so a running export always finishes
+        // errors are already logged, the next tick tries again
+        let result = export_stats_guarded(&export_config, &client, &state).await;
+        health.set(result.is_ok());
+
+        if let Err(e) = write_textfile(textfile, prometheus.as_ref()) {
+            error!("Could not write textfile: {}", e);
+        }
+        if let Err(e) = push_metrics(push_gateway.as_ref(), prometheus.as_ref()).await {
+            error!("Could not push metrics: {}", e);
+        }
+        if let Err(e) = push_otlp(otlp.as_ref()).await {
+            error!("Could not push metrics to the collector: {}", e);
+        }
+    }
+
+    info!("Shutdown requested, stopping");
+    data_age.abort();
+
+    if let Some((stop, handle)) = live_feed {
+        // fails if the feed stopped on its own already
+        let _ = stop.send(());
+        handle.await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct Dump<'a> {
+    stats: Vec<ChatStats<'a>>,
+    top_channels: Cow<'a, [Channel<'a>]>,
+}
+
+/// The scrape endpoint could not listen on its address.
+#[derive(Debug, thiserror::Error)]
+#[error("failed to bind metrics server to {address}: {reason}")]
+struct BindError {
+    address: SocketAddr,
+    reason: String,
+}
+
+/// Everything that stops the exporter early.
+#[derive(Debug, thiserror::Error)]
+enum AppError {
+    #[error(transparent)]
+    Settings(#[from] SettingsError),
+
+    #[error(transparent)]
+    LabelKey(#[from] LabelKeyError),
+
+    #[error(transparent)]
+    Filter(#[from] FilterError),
+
+    #[error("{proxy:?} is not a valid proxy")]
+    InvalidProxy {
+        proxy: String,
+        source: reqwest::Error,
+    },
+
+    /// Flags that do not work together or values clap does not check.
+    #[error("{0}")]
+    Usage(String),
+
+    #[error(transparent)]
+    Bind(#[from] BindError),
+
+    #[error("Could not open a socket for statsd")]
+    Statsd(#[source] io::Error),
+
+    #[error("Could not install the metrics recorder")]
+    Recorder(#[from] SetRecorderError),
+
+    #[error("Could not build http client")]
+    Client(#[source] reqwest::Error),
+
+    #[error(transparent)]
+    Api(#[from] stats_api::Error),
+
+    // boxed, it would make every other variant as large
+    #[error(transparent)]
+    Ws(Box<stats_ws::Error>),
+
+    #[error(transparent)]
+    Cycle(#[from] CycleError),
+
+    #[error("Could not write textfile")]
+    Textfile(#[source] io::Error),
+
+    #[error("Could not push metrics")]
+    Push(#[source] reqwest::Error),
+
+    #[error("Could not print {path}")]
+    Normalize {
+        path: String,
+        source: toml::ser::Error,
+    },
+
+    #[error("Could not print the dump")]
+    Dump(#[from] serde_json::Error),
+
+    #[error("The live feed did not stop cleanly")]
+    LiveFeed(#[from] JoinError),
+}
+
+impl AppError {
+    /// The process exit code.
+    fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Settings(_)
+            | AppError::LabelKey(_)
+            | AppError::Filter(_)
+            | AppError::InvalidProxy { .. }
+            | AppError::Usage(_) => EXIT_INVALID,
+            AppError::Bind(_) | AppError::Statsd(_) => EXIT_SOCKET,
+            AppError::Api(stats_api::Error::BuildClientError(_))
+            | AppError::Cycle(CycleError::Fetch(stats_api::Error::BuildClientError(_))) => {
+                EXIT_FAILURE
+            }
+            AppError::Api(_) | AppError::Ws(_) | AppError::Cycle(CycleError::Fetch(_)) => EXIT_API,
+            _ => EXIT_FAILURE,
+        }
+    }
+}
+
+/// Like [`PrometheusBuilder::build_with_exporter`], but says which address failed and why.
+fn bind_exporter(
+    builder: PrometheusBuilder,
+    address: SocketAddr,
+) -> Result<
+    (
+        PrometheusRecorder,
+        impl future::Future<Output = Result<(), hyper::Error>>,
+    ),
+    BindError,
+> {
+    builder
+        .listen_address(address)
+        .build_with_exporter()
+        .map_err(|e| {
+            // the io error, e.g. "Address already in use", is buried in hyper's error
+            let mut source: Option<&(dyn Error + 'static)> = Some(&e);
+            while let Some(error) = source {
+                if let Some(io_error) = error.downcast_ref::<io::Error>() {
+                    return BindError {
+                        address,
+                        reason: io_error.to_string(),
+                    };
+                }
+                source = error.source();
+            }
+
+            BindError {
+                address,
+                reason: e.to_string(),
+            }
+        })
+}
+
+/// Fetch everything as is and print it to stdout.
+async fn dump_stats(client: &ApiClient, channels: &[String]) -> Result<(), AppError> {
+    let mut stats = Vec::with_capacity(channels.len());
+    for channel in channels {
+        stats.push(client.get_stats(channel).await?);
+    }
+
+    let dump = Dump {
+        stats,
+        top_channels: client.get_top_channels().await?,
+    };
+    println!("{}", serde_json::to_string_pretty(&dump)?);
+
+    Ok(())
+}
+
+/// Print the schema of every export, with the names the backend will show.
+fn list_exports(config: &ExportConfig, backend: Backend) {
+    // the prometheus exporter turns dots into underscores anyway
+    let separator = match backend {
+        Backend::Prometheus => MetricSeparator::Underscore,
+        Backend::Statsd | Backend::Otlp => config.separator(),
+    };
+
+    for &export in ExportName::ALL.iter() {
+        println!("{}", export);
+        for series in config.schema(export) {
+            println!(
+                "    {}{{{}}}  {}",
+                series.metric.name(config.prefix(), separator),
+                series.labels.join(", "),
+                series.metric.description()
+            );
+        }
+    }
+}
+
+/// Render the registry into the textfile, if one is configured.
+fn write_textfile(path: Option<&Path>, handle: Option<&MetricsHandle>) -> io::Result<()> {
+    match (path, handle) {
+        (Some(path), Some(handle)) => textfile::write_atomic(path, &handle.render()),
+        _ => Ok(()),
+    }
+}
+
+/// Render the registry and push it, if a gateway is configured.
+async fn push_metrics(
+    gateway: Option<&PushGateway>,
+    handle: Option<&MetricsHandle>,
+) -> Result<(), reqwest::Error> {
+    match (gateway, handle) {
+        (Some(gateway), Some(handle)) => gateway.push(handle.render()).await,
+        _ => Ok(()),
+    }
+}
+
+/// Push the current values to the collector, if the otlp backend is used.
+async fn push_otlp(otlp: Option<&(OtlpExporter, &OtlpRecorder)>) -> Result<(), reqwest::Error> {
+    match otlp {
+        Some((exporter, recorder)) => exporter.push(recorder).await,
+        None => Ok(()),
+    }
+}
+
+/// Wait for ctrl-c or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let result = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(mut terminate) => tokio::select! {
+            result = signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        },
+        Err(e) => Err(e),
+    };
+    #[cfg(not(unix))]
+    let result = signal::ctrl_c().await;
+
+    if let Err(e) = result {
+        error!("Could not listen for shutdown signals: {}", e);
+        // keep running, there is just no clean way to stop
+        future::pending::<()>().await;
     }
 }
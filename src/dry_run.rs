@@ -0,0 +1,246 @@
+use crate::sink::MetricSink;
+use metrics::{GaugeValue, Key, KeyData, Recorder, Unit};
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+use tracing::info;
+
+/// A recorder that keeps metrics in memory so they can be printed instead of served.
+///
+/// It is a [`MetricSink`] as well, for an [`ExportState`](crate::state::ExportState) of its own.
+#[derive(Debug, Default)]
+pub struct CollectingRecorder {
+    values: Mutex<BTreeMap<KeyData, f64>>,
+}
+
+impl CollectingRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Render every collected metric as a table of name, value and labels.
+    pub fn render_table(&self) -> String {
+        let rows: Vec<(String, String, String)> = self
+            .values
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| {
+                (
+                    key.name().to_string(),
+                    value.to_string(),
+                    format_labels(key),
+                )
+            })
+            .collect();
+
+        let name_width = rows.iter().map(|row| row.0.len()).fold(4, usize::max);
+        let value_width = rows.iter().map(|row| row.1.len()).fold(5, usize::max);
+
+        let mut table = String::new();
+        let mut line = |name: &str, value: &str, labels: &str| {
+            let line = format!(
+                "{:name_width$}  {:>value_width$}  {}",
+                name,
+                value,
+                labels,
+                name_width = name_width,
+                value_width = value_width
+            );
+            writeln!(table, "{}", line.trim_end()).unwrap();
+        };
+
+        line("NAME", "VALUE", "LABELS");
+        for (name, value, labels) in rows.iter() {
+            line(name, value, labels);
+        }
+
+        table
+    }
+}
+
+impl Recorder for CollectingRecorder {
+    fn register_counter(&self, _key: Key, _unit: Option<Unit>, _description: Option<&'static str>) {
+    }
+
+    fn register_gauge(&self, _key: Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn register_histogram(
+        &self,
+        _key: Key,
+        _unit: Option<Unit>,
+        _description: Option<&'static str>,
+    ) {
+    }
+
+    fn increment_counter(&self, key: Key, value: u64) {
+        *self
+            .values
+            .lock()
+            .unwrap()
+            .entry(key.into_owned())
+            .or_default() += value as f64;
+    }
+
+    fn update_gauge(&self, key: Key, value: GaugeValue) {
+        let mut values = self.values.lock().unwrap();
+        let entry = values.entry(key.into_owned()).or_default();
+        *entry = value.update_value(*entry);
+    }
+
+    fn record_histogram(&self, _key: Key, _value: f64) {}
+}
+
+fn format_labels(key: &KeyData) -> String {
+    key.labels()
+        .map(|label| format!("{}={:?}", label.key(), label.value()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// A recorder that logs every update at info level instead of keeping it.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LoggingRecorder;
+
+impl LoggingRecorder {
+    pub fn new() -> Self {
+        LoggingRecorder
+    }
+}
+
+impl Recorder for LoggingRecorder {
+    fn register_counter(&self, _key: Key, _unit: Option<Unit>, _description: Option<&'static str>) {
+    }
+
+    fn register_gauge(&self, _key: Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn register_histogram(
+        &self,
+        _key: Key,
+        _unit: Option<Unit>,
+        _description: Option<&'static str>,
+    ) {
+    }
+
+    fn increment_counter(&self, key: Key, value: u64) {
+        info!(
+            name = %key.name(),
+            value = %format!("+{}", value),
+            labels = %format_labels(&key),
+            "counter"
+        );
+    }
+
+    fn update_gauge(&self, key: Key, value: GaugeValue) {
+        let value = match value {
+            GaugeValue::Absolute(value) => value.to_string(),
+            GaugeValue::Increment(value) => format!("+{}", value),
+            GaugeValue::Decrement(value) => format!("-{}", value),
+        };
+        info!(
+            name = %key.name(),
+            value = %value,
+            labels = %format_labels(&key),
+            "gauge"
+        );
+    }
+
+    fn record_histogram(&self, _key: Key, _value: f64) {}
+}
+
+impl MetricSink for CollectingRecorder {
+    fn gauge(&self, key: &KeyData, value: f64) {
+        self.update_gauge(Key::Owned(key.clone()), GaugeValue::Absolute(value));
+    }
+
+    fn counter(&self, key: &KeyData, value: u64) {
+        self.increment_counter(Key::Owned(key.clone()), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CollectingRecorder;
+    use crate::{
+        export_stats,
+        state::ExportState,
+        stats_api::{mock::MockApi, ApiClient},
+        ExportConfig, ExportName,
+    };
+    use metrics::{GaugeValue, Key, KeyData, Label, Recorder};
+    use std::sync::Arc;
+
+    #[test]
+    fn renders_collected_metrics() {
+        let recorder = CollectingRecorder::new();
+
+        recorder.update_gauge(
+            Key::Owned(KeyData::from_parts(
+                "sestats.emote",
+                vec![
+                    Label::new("provider", "ffz"),
+                    Label::new("emote", "OMEGALUL"),
+                ],
+            )),
+            GaugeValue::Absolute(521226913.0),
+        );
+        recorder.update_gauge(
+            Key::Owned(KeyData::from_name("sestats.total-messages")),
+            GaugeValue::Absolute(67397996744.0),
+        );
+        recorder.increment_counter(
+            Key::Owned(KeyData::from_parts(
+                "sestats.api_requests_total",
+                vec![Label::new("status", "2xx")],
+            )),
+            2,
+        );
+
+        assert_eq!(
+            recorder.render_table(),
+            "\
+NAME                              VALUE  LABELS
+sestats.api_requests_total            2  status=\"2xx\"
+sestats.emote                 521226913  provider=\"ffz\",emote=\"OMEGALUL\"
+sestats.total-messages      67397996744
+"
+        );
+    }
+
+    #[tokio::test]
+    async fn renders_an_export_of_the_fixture() {
+        let api = MockApi::start();
+        let client = ApiClient::new().unwrap().with_base_url(api.url());
+        let config = ExportConfig::from(vec![ExportName::Chatter, ExportName::TotalMessages]);
+        let recorder = Arc::new(CollectingRecorder::new());
+        let state = ExportState::with_sink(recorder.clone());
+
+        export_stats(&config, &client, &state).await.unwrap();
+
+        // timings, timestamps and the build change with every run
+        let volatile = [
+            "sestats.build_info",
+            "sestats.channel_last_success_timestamp_seconds",
+            "sestats.export_duration_seconds",
+            "sestats.last_success_timestamp_seconds",
+        ];
+        // the alignment depends on the volatile rows too, it is covered above
+        let rows: Vec<String> = recorder
+            .render_table()
+            .lines()
+            .filter(|line| !volatile.iter().any(|name| line.starts_with(name)))
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect();
+
+        assert_eq!(
+            rows,
+            [
+                "NAME VALUE LABELS",
+                "sestats.chatter 102934811 channel=\"global\",name=\"nightbot\"",
+                "sestats.chatter 98211034 channel=\"global\",name=\"streamelements\"",
+                "sestats.chatter_total 201145845 channel=\"global\"",
+                "sestats.series_count 2 category=\"chatter\",channel=\"global\"",
+                "sestats.total-messages 67397996744 channel=\"global\"",
+                "sestats.up 1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use crate::{
+    names::{register_metrics, set_build_info},
+    sink::MetricSink,
+    ExportConfig,
+};
+use metrics::{GaugeValue, Key, KeyData, Recorder, SetRecorderError, Unit};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle, PrometheusRecorder};
+use metrics_util::MetricKindMask;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// The Prometheus exposition of everything exported so far, for serving `/metrics` yourself.
+///
+/// Only one recorder can be installed per process, so this and
+/// [`PrometheusBuilder::install`] are mutually exclusive.
+#[derive(Clone)]
+pub struct MetricsHandle {
+    handle: PrometheusHandle,
+}
+
+impl MetricsHandle {
+    /// Render the current values in the text exposition format.
+    pub fn render(&self) -> String {
+        self.handle.render()
+    }
+
+    pub fn prometheus(&self) -> &PrometheusHandle {
+        &self.handle
+    }
+}
+
+// PrometheusHandle has no Debug
+impl fmt::Debug for MetricsHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetricsHandle").finish_non_exhaustive()
+    }
+}
+
+/// A prometheus recorder whose gauges only expire after they were removed.
+///
+/// The prometheus recorder cannot remove a single series, it drops every gauge that was
+/// not updated within its idle timeout. This keeps the last value of each gauge and sets
+/// it again on [`refresh`](Self::refresh) until [`MetricSink::remove`] drops it, so gauges
+/// like `build_info` or `up` stay while the series [`ExportState`](crate::state::ExportState)
+/// removes expire.
+#[derive(Clone)]
+pub struct ExpiringRecorder {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    recorder: PrometheusRecorder,
+    idle_timeout: Option<Duration>,
+    gauges: Mutex<HashMap<KeyData, f64>>,
+}
+
+impl ExpiringRecorder {
+    /// Wrap `recorder`, which has to be built with `idle_timeout` for gauges.
+    pub fn new(recorder: PrometheusRecorder, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                recorder,
+                idle_timeout,
+                gauges: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.inner.idle_timeout
+    }
+
+    pub fn handle(&self) -> MetricsHandle {
+        MetricsHandle {
+            handle: self.inner.recorder.handle(),
+        }
+    }
+
+    /// Render the current values in the text exposition format.
+    pub fn render(&self) -> String {
+        self.inner.recorder.handle().render()
+    }
+
+    /// Set every gauge that was not removed again, so it does not idle out.
+    ///
+    /// Has to run more often than the idle timeout, every half of it is plenty.
+    pub fn refresh(&self) {
+        let gauges = self.inner.gauges.lock().unwrap();
+        for (key, value) in gauges.iter() {
+            self.inner
+                .recorder
+                .update_gauge(Key::Owned(key.clone()), GaugeValue::Absolute(*value));
+        }
+    }
+}
+
+// PrometheusRecorder has no Debug
+impl fmt::Debug for ExpiringRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExpiringRecorder")
+            .field("idle_timeout", &self.inner.idle_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Recorder for ExpiringRecorder {
+    fn register_counter(&self, key: Key, unit: Option<Unit>, description: Option<&'static str>) {
+        self.inner.recorder.register_counter(key, unit, description)
+    }
+
+    fn register_gauge(&self, key: Key, unit: Option<Unit>, description: Option<&'static str>) {
+        self.inner.recorder.register_gauge(key, unit, description)
+    }
+
+    fn register_histogram(&self, key: Key, unit: Option<Unit>, description: Option<&'static str>) {
+        self.inner
+            .recorder
+            .register_histogram(key, unit, description)
+    }
+
+    fn increment_counter(&self, key: Key, value: u64) {
+        self.inner.recorder.increment_counter(key, value)
+    }
+
+    fn update_gauge(&self, key: Key, value: GaugeValue) {
+        if self.inner.idle_timeout.is_none() {
+            return self.inner.recorder.update_gauge(key, value);
+        }
+
+        // hold the lock while forwarding, a refresh must not overtake the update
+        let mut gauges = self.inner.gauges.lock().unwrap();
+        let key = key.into_owned();
+        let current = gauges.entry(key.clone()).or_insert(0.0);
+        *current = value.update_value(*current);
+        self.inner
+            .recorder
+            .update_gauge(Key::Owned(key), GaugeValue::Absolute(*current));
+    }
+
+    fn record_histogram(&self, key: Key, value: f64) {
+        self.inner.recorder.record_histogram(key, value)
+    }
+}
+
+impl MetricSink for ExpiringRecorder {
+    fn gauge(&self, key: &KeyData, value: f64) {
+        self.update_gauge(Key::Owned(key.clone()), GaugeValue::Absolute(value));
+    }
+
+    fn counter(&self, key: &KeyData, value: u64) {
+        self.increment_counter(Key::Owned(key.clone()), value);
+    }
+
+    fn remove(&self, key: &KeyData) {
+        self.inner.gauges.lock().unwrap().remove(key);
+    }
+}
+
+/// Build a recorder that expires gauges like the binary does, without listening anywhere.
+///
+/// `interval` and `jitter` are how often [`export_stats`](crate::export_stats) will be called.
+pub fn build_recorder(
+    config: &ExportConfig,
+    interval: Duration,
+    jitter: Duration,
+) -> (ExpiringRecorder, MetricsHandle) {
+    let idle_timeout = config.failure_policy().idle_timeout(interval, jitter);
+    let recorder = PrometheusBuilder::new()
+        .idle_timeout(MetricKindMask::GAUGE, idle_timeout)
+        .build();
+    let recorder = ExpiringRecorder::new(recorder, idle_timeout);
+    let handle = recorder.handle();
+
+    (recorder, handle)
+}
+
+/// Install the recorder from [`build_recorder`] and register every metric with it.
+///
+/// Pass the returned recorder to [`ExportState::with_sink`](crate::state::ExportState::with_sink)
+/// and [`refresh`](ExpiringRecorder::refresh) it regularly when series expire.
+///
+/// ```
+/// use se_stats_exporter::{exposition::install_recorder, state::ExportState, ExportConfig};
+/// use std::{sync::Arc, time::Duration};
+///
+/// let config = ExportConfig::default();
+/// let recorder = install_recorder(&config, Duration::from_secs(60), Duration::ZERO).unwrap();
+/// let _state = ExportState::with_sink(Arc::new(recorder.clone()));
+///
+/// assert!(recorder.render().contains("sestats_build_info"));
+/// ```
+pub fn install_recorder(
+    config: &ExportConfig,
+    interval: Duration,
+    jitter: Duration,
+) -> Result<ExpiringRecorder, SetRecorderError> {
+    let (recorder, _) = build_recorder(config, interval, jitter);
+    metrics::set_boxed_recorder(Box::new(recorder.clone()))?;
+
+    register_metrics(config.prefix(), config.separator(), config.amounts());
+    set_build_info(config.prefix(), config.separator());
+
+    Ok(recorder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_recorder, ExpiringRecorder};
+    use crate::{sink::MetricSink, ExportConfig};
+    use metrics::{GaugeValue, Key, KeyData, Recorder};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use metrics_util::MetricKindMask;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn handle_renders_the_recorder() {
+        let (recorder, handle) = build_recorder(
+            &ExportConfig::default(),
+            Duration::from_secs(60),
+            Duration::ZERO,
+        );
+
+        recorder.update_gauge(
+            Key::Owned(KeyData::from_name("sestats_up")),
+            GaugeValue::Absolute(1.0),
+        );
+
+        assert!(handle.render().contains("sestats_up 1"));
+    }
+
+    #[test]
+    fn only_removed_gauges_expire() {
+        let idle_timeout = Some(Duration::from_millis(1));
+        let recorder = ExpiringRecorder::new(
+            PrometheusBuilder::new()
+                .idle_timeout(MetricKindMask::GAUGE, idle_timeout)
+                .build(),
+            idle_timeout,
+        );
+        let emote = KeyData::from_name("sestats_emote");
+
+        recorder.update_gauge(
+            Key::Owned(KeyData::from_name("sestats_build_info")),
+            GaugeValue::Absolute(1.0),
+        );
+        recorder.gauge(&emote, 42.0);
+        recorder.update_gauge(Key::Owned(emote.clone()), GaugeValue::Increment(1.0));
+        // the recorder only notices idle series while rendering
+        assert!(recorder.render().contains("sestats_emote 43"));
+
+        recorder.remove(&emote);
+        thread::sleep(Duration::from_millis(50));
+        recorder.refresh();
+
+        let output = recorder.render();
+        assert!(output.contains("sestats_build_info 1"));
+        assert!(!output.contains("sestats_emote"));
+    }
+}
@@ -0,0 +1,179 @@
+use regex::Regex;
+use std::{fs, io, path::Path};
+
+#[derive(Debug, thiserror::Error)]
+pub enum FilterError {
+    #[error("Unknown metric {0:?}, expected one of chatter, hashtag, command, emote or channel")]
+    UnknownMetric(String),
+
+    #[error("{pattern:?} is not a valid regex: {source}")]
+    InvalidRegex {
+        pattern: String,
+        source: regex::Error,
+    },
+
+    #[error("Could not read patterns from {path}: {source}")]
+    ReadError { path: String, source: io::Error },
+}
+
+/// One entry of an allow or deny list.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Exact(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// Parse `/.../` as a regex and anything else as an exact name.
+    ///
+    /// Regexes match anywhere in the name unless they are anchored with `^` and `$`.
+    pub fn parse(pattern: &str) -> Result<Self, FilterError> {
+        match pattern
+            .strip_prefix('/')
+            .and_then(|rest| rest.strip_suffix('/'))
+        {
+            Some(regex) if !regex.is_empty() => {
+                Regex::new(regex)
+                    .map(Pattern::Regex)
+                    .map_err(|source| FilterError::InvalidRegex {
+                        pattern: pattern.to_string(),
+                        source,
+                    })
+            }
+            _ => Ok(Pattern::Exact(pattern.to_string())),
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Pattern::Exact(exact) => exact == name,
+            Pattern::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Pattern::Exact(a), Pattern::Exact(b)) => a == b,
+            (Pattern::Regex(a), Pattern::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+/// The allow and deny list of one metric.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NameFilter {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+}
+
+impl NameFilter {
+    /// Denied names are always skipped. Once anything is allowed, only allowed names are kept.
+    pub fn allows(&self, name: &str) -> bool {
+        !self.deny.iter().any(|pattern| pattern.matches(name))
+            && (self.allow.is_empty() || self.allow.iter().any(|pattern| pattern.matches(name)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+/// The allow and deny lists for the entry names of each metric.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filters {
+    pub chatter: NameFilter,
+    pub hashtag: NameFilter,
+    pub command: NameFilter,
+    pub emote: NameFilter,
+    pub channel: NameFilter,
+}
+
+impl Filters {
+    /// Only export the entries of `metric` that match `pattern`, or one of the other allowed ones.
+    pub fn allow(&mut self, metric: &str, pattern: &str) -> Result<(), FilterError> {
+        let pattern = Pattern::parse(pattern)?;
+        self.slot(metric)?.allow.push(pattern);
+        Ok(())
+    }
+
+    /// Skip the entries of `metric` that match `pattern`.
+    pub fn deny(&mut self, metric: &str, pattern: &str) -> Result<(), FilterError> {
+        let pattern = Pattern::parse(pattern)?;
+        self.slot(metric)?.deny.push(pattern);
+        Ok(())
+    }
+
+    fn slot(&mut self, metric: &str) -> Result<&mut NameFilter, FilterError> {
+        match metric {
+            "chatter" => Ok(&mut self.chatter),
+            "hashtag" => Ok(&mut self.hashtag),
+            "command" => Ok(&mut self.command),
+            "emote" => Ok(&mut self.emote),
+            "channel" => Ok(&mut self.channel),
+            _ => Err(FilterError::UnknownMetric(metric.to_string())),
+        }
+    }
+}
+
+/// Read one pattern per line, skipping empty lines and lines starting with `#`.
+pub fn read_patterns(path: &Path) -> Result<Vec<String>, FilterError> {
+    let content = fs::read_to_string(path).map_err(|source| FilterError::ReadError {
+        path: path.display().to_string(),
+        source,
+    })?;
+
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_patterns, FilterError, Filters, Pattern};
+    use std::{env, fs, process};
+
+    #[test]
+    fn deny_beats_allow() {
+        let mut filters = Filters::default();
+        filters.allow("chatter", "/^x/").unwrap();
+        filters.deny("chatter", "/bot$/").unwrap();
+        filters.deny("emote", "KEKW").unwrap();
+
+        assert!(filters.chatter.allows("xqcow"));
+        assert!(!filters.chatter.allows("xbot"));
+        assert!(!filters.chatter.allows("forsen"));
+        assert!(filters.emote.allows("OMEGALUL"));
+        assert!(!filters.emote.allows("KEKW"));
+        assert!(filters.channel.is_empty());
+    }
+
+    #[test]
+    fn patterns_are_validated() {
+        assert!(matches!(Pattern::parse("/"), Ok(Pattern::Exact(exact)) if exact == "/"));
+        assert!(matches!(
+            Pattern::parse("/(/"),
+            Err(FilterError::InvalidRegex { .. })
+        ));
+        assert!(matches!(
+            Filters::default().deny("chatters", "forsen"),
+            Err(FilterError::UnknownMetric(_))
+        ));
+    }
+
+    #[test]
+    fn pattern_files_skip_comments() {
+        let path = env::temp_dir().join(format!("se-stats-exporter-deny-{}", process::id()));
+        fs::write(&path, "# bots\nnightbot\n\n/bot$/\n").unwrap();
+
+        let patterns = read_patterns(&path).unwrap();
+
+        assert_eq!(patterns, ["nightbot", "/bot$/"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use crate::stats_api::ApiClient;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// How long `/readyz` answers with the last result instead of asking the api again.
+///
+/// Probes every few seconds from every replica would otherwise add up upstream.
+pub const READY_TTL: Duration = Duration::from_secs(30);
+/// Whether the last export cycle succeeded, shared with the health endpoint.
+#[derive(Debug, Default, Clone)]
+pub struct Health(Arc<AtomicBool>);
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, healthy: bool) {
+        self.0.store(healthy, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The result of the last [`ApiClient::healthcheck`], shared between requests.
+#[derive(Debug, Clone)]
+struct Readiness {
+    client: ApiClient,
+    ttl: Duration,
+    last: Arc<Mutex<Option<(Instant, bool)>>>,
+}
+
+impl Readiness {
+    fn new(client: ApiClient, ttl: Duration) -> Self {
+        Readiness {
+            client,
+            ttl,
+            last: Arc::default(),
+        }
+    }
+
+    /// Whether the api is reachable, checked again once the last result is `ttl` old.
+    async fn check(&self) -> bool {
+        if let Some((checked_at, ready)) = *self.last.lock().unwrap() {
+            if checked_at.elapsed() < self.ttl {
+                return ready;
+            }
+        }
+
+        let ready = match self.client.healthcheck().await {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Readiness check failed: {}", e);
+                false
+            }
+        };
+        *self.last.lock().unwrap() = Some((Instant::now(), ready));
+
+        ready
+    }
+}
+
+/// Serve `/healthz` and `/readyz` on `address`.
+///
+/// `/healthz` answers 200 after a successful export, 503 before the first one and after
+/// a failed one. `/readyz` answers 200 when [`ApiClient::healthcheck`] reaches the api
+/// with `client` and 503 when it does not, the result is reused for [`READY_TTL`].
+pub async fn serve(
+    address: SocketAddr,
+    health: Health,
+    client: ApiClient,
+) -> Result<(), hyper::Error> {
+    let readiness = Readiness::new(client, READY_TTL);
+    let make_service = make_service_fn(move |_| {
+        let health = health.clone();
+        let readiness = readiness.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let health = health.clone();
+                let readiness = readiness.clone();
+                async move { Ok::<_, Infallible>(respond(&request, &health, &readiness).await) }
+            }))
+        }
+    });
+
+    Server::try_bind(&address)?.serve(make_service).await
+}
+
+async fn respond(
+    request: &Request<Body>,
+    health: &Health,
+    readiness: &Readiness,
+) -> Response<Body> {
+    let (status, body) = match request.uri().path() {
+        "/healthz" if health.get() => (StatusCode::OK, "ok\n"),
+        "/healthz" => (StatusCode::SERVICE_UNAVAILABLE, "last export failed\n"),
+        "/readyz" if readiness.check().await => (StatusCode::OK, "ok\n"),
+        "/readyz" => (StatusCode::SERVICE_UNAVAILABLE, "api unreachable\n"),
+        _ => (StatusCode::NOT_FOUND, "not found\n"),
+    };
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{respond, Health, Readiness, READY_TTL};
+    use crate::stats_api::{mock::MockApi, ApiClient};
+    use hyper::{Body, Request, StatusCode};
+    use reqwest::Url;
+    use std::{net::TcpListener, time::Duration};
+
+    async fn status(path: &str, healthy: bool, client: &ApiClient) -> StatusCode {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        let health = Health::new();
+        health.set(healthy);
+        let readiness = Readiness::new(client.clone(), READY_TTL);
+        respond(&request, &health, &readiness).await.status()
+    }
+
+    fn client(base_url: Url) -> ApiClient {
+        ApiClient::new()
+            .unwrap()
+            .with_base_url(base_url)
+            .with_retries(0, Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn healthz_reflects_last_export() {
+        let client = ApiClient::new().unwrap();
+
+        assert_eq!(status("/healthz", true, &client).await, StatusCode::OK);
+        assert_eq!(
+            status("/healthz", false, &client).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status("/metrics", true, &client).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn readyz_checks_the_api() {
+        let api = MockApi::start();
+
+        // readiness does not care about the last export
+        assert_eq!(
+            status("/readyz", false, &client(api.url())).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status("/readyz", true, &client(api.url().join("down/").unwrap())).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(api.requests(), ["/chatstats", "/down/chatstats"]);
+    }
+
+    #[tokio::test]
+    async fn readiness_is_checked_again_after_the_ttl() {
+        let api = MockApi::start();
+        let readiness = Readiness::new(client(api.url()), Duration::from_millis(100));
+
+        assert!(readiness.check().await);
+        assert!(readiness.check().await);
+        assert_eq!(api.requests().len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(readiness.check().await);
+        assert_eq!(api.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn readyz_fails_when_the_api_is_unreachable() {
+        // nothing listens on a port that was just freed
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let base_url = Url::parse(&format!("http://{}/", address)).unwrap();
+
+        assert_eq!(
+            status("/readyz", true, &client(base_url)).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
@@ -0,0 +1,159 @@
+#[derive(Debug, thiserror::Error)]
+pub enum LabelKeyError {
+    #[error("Unknown metric {0:?}, expected one of chatter, hashtag, command, emote or channel")]
+    UnknownMetric(String),
+
+    #[error("{0:?} is not a valid prometheus label name")]
+    InvalidLabelName(String),
+
+    #[error("{0:?} is already used by a fixed label of the exported series")]
+    ReservedLabelName(String),
+}
+
+/// Labels the exporter sets itself next to the entry name.
+const FIXED_LABELS: [&str; 9] = [
+    "channel", "provider", "window", "emote_id", "scope", "width", "height", "gif", "username",
+];
+
+/// The label keys used for the entry name of each metric.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelKeys {
+    pub chatter: String,
+    pub hashtag: String,
+    pub command: String,
+    pub emote: String,
+    pub channel: String,
+}
+
+impl Default for LabelKeys {
+    fn default() -> Self {
+        Self {
+            chatter: String::from("name"),
+            hashtag: String::from("hashtag"),
+            command: String::from("command"),
+            emote: String::from("emote"),
+            channel: String::from("channel"),
+        }
+    }
+}
+
+impl LabelKeys {
+    /// Apply `(metric, label key)` overrides on top of the default keys.
+    pub fn from_overrides<I, M, K>(overrides: I) -> Result<Self, LabelKeyError>
+    where
+        I: IntoIterator<Item = (M, K)>,
+        M: AsRef<str>,
+        K: AsRef<str>,
+    {
+        let mut keys = Self::default();
+
+        for (metric, key) in overrides {
+            keys.set(metric.as_ref(), key.as_ref())?;
+        }
+
+        Ok(keys)
+    }
+
+    /// Override the label key of a single metric.
+    ///
+    /// Keys that clash with a fixed label are rejected, except for the default key of the
+    /// metric itself, the top channels are labeled by `channel` alone.
+    pub fn set(&mut self, metric: &str, key: &str) -> Result<(), LabelKeyError> {
+        if !is_valid_label_name(key) {
+            return Err(LabelKeyError::InvalidLabelName(key.to_string()));
+        }
+
+        let default = Self::default();
+        let (slot, default) = match metric {
+            "chatter" => (&mut self.chatter, default.chatter),
+            "hashtag" => (&mut self.hashtag, default.hashtag),
+            "command" => (&mut self.command, default.command),
+            "emote" => (&mut self.emote, default.emote),
+            "channel" => (&mut self.channel, default.channel),
+            _ => return Err(LabelKeyError::UnknownMetric(metric.to_string())),
+        };
+        if key != default && FIXED_LABELS.contains(&key) {
+            return Err(LabelKeyError::ReservedLabelName(key.to_string()));
+        }
+        *slot = key.to_string();
+
+        Ok(())
+    }
+}
+
+/// Check a label name against `[a-zA-Z_][a-zA-Z0-9_]*`, rejecting the reserved `__` prefix.
+pub fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+
+    !name.starts_with("__") && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_label_name, LabelKeyError, LabelKeys};
+
+    #[test]
+    fn label_name_validation() {
+        assert!(is_valid_label_name("user"));
+        assert!(is_valid_label_name("emote_name"));
+        assert!(is_valid_label_name("_private"));
+        assert!(!is_valid_label_name(""));
+        assert!(!is_valid_label_name("1st"));
+        assert!(!is_valid_label_name("emote-name"));
+        assert!(!is_valid_label_name("__name__"));
+    }
+
+    #[test]
+    fn overrides_replace_defaults() {
+        let keys =
+            LabelKeys::from_overrides(vec![("chatter", "user"), ("emote", "emote_name")]).unwrap();
+
+        assert_eq!(keys.chatter, "user");
+        assert_eq!(keys.emote, "emote_name");
+        assert_eq!(keys.hashtag, LabelKeys::default().hashtag);
+    }
+
+    #[test]
+    fn overrides_are_validated() {
+        assert!(matches!(
+            LabelKeys::from_overrides(vec![("chatters", "user")]),
+            Err(LabelKeyError::UnknownMetric(_))
+        ));
+        assert!(matches!(
+            LabelKeys::from_overrides(vec![("chatter", "user name")]),
+            Err(LabelKeyError::InvalidLabelName(_))
+        ));
+    }
+
+    #[test]
+    fn fixed_labels_are_reserved() {
+        for &(metric, key) in [
+            ("chatter", "channel"),
+            ("emote", "emote_id"),
+            ("emote", "provider"),
+            ("hashtag", "window"),
+            ("channel", "username"),
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(
+                    LabelKeys::from_overrides(vec![(metric, key)]),
+                    Err(LabelKeyError::ReservedLabelName(_))
+                ),
+                "{} = {}",
+                metric,
+                key
+            );
+        }
+
+        // top channels carry no other channel label
+        let keys = LabelKeys::from_overrides(vec![("channel", "channel")]).unwrap();
+        assert_eq!(keys, LabelKeys::default());
+    }
+}
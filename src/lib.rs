@@ -1,28 +1,146 @@
 #![warn(missing_copy_implementations, missing_debug_implementations)]
 
-use clap::arg_enum;
-use metrics::{gauge, IntoLabels, Label};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use stats_api::{ApiClient, ChatterStats};
-use tracing::{debug, error, instrument};
+use chrono::Utc;
+use filter::{Filters, NameFilter};
+use futures_util::{
+    future,
+    stream::{self, Stream, StreamExt},
+    FutureExt,
+};
+use labels::LabelKeys;
+use metrics::Label;
+use names::{AmountKind, Metric, MetricSeparator, DEFAULT_PREFIX};
+use parallel::{Entries, IntoEntries};
+use sanitize::{sanitize_entries, Sanitize, Sanitized};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sink::{ChannelSink, MetricSample};
+use state::{ExportState, FailurePolicy};
+use stats_api::{
+    ApiClient, Channel, ChatEmotes, ChatStats, ChatterStats, CommandStats, Emote, EmoteStats,
+    HashtagStats, StatsWindow,
+};
+use stats_ws::{StatsChangeMessage, WsClient};
+use std::{
+    any::Any,
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fmt,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tracing::{debug, debug_span, error, info, instrument, warn, Instrument};
 
+pub mod dry_run;
+pub mod exposition;
+pub mod filter;
+pub mod health;
+pub mod labels;
+pub mod names;
+pub mod otlp;
+pub mod parallel;
+pub mod push;
+pub mod sanitize;
+pub mod schedule;
+pub mod settings;
+pub mod sink;
+pub mod state;
 pub mod stats_api;
+pub mod stats_ws;
+pub mod statsd;
+pub mod textfile;
 
-arg_enum! {
-    #[derive(PartialEq, Debug)]
-    pub enum ExportName {
-        Bttv,
-        Ffz,
-        Twitch,
-        Hashtag,
-        Command,
-        Chatter,
-        Channel,
-        TotalMessages,
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum ExportName {
+    Bttv,
+    Ffz,
+    Twitch,
+    Hashtag,
+    Command,
+    Chatter,
+    Channel,
+    TotalMessages,
+    ChatEmotes,
+}
+
+impl ExportName {
+    pub const ALL: [ExportName; 9] = [
+        ExportName::Bttv,
+        ExportName::Ffz,
+        ExportName::Twitch,
+        ExportName::Hashtag,
+        ExportName::Command,
+        ExportName::Chatter,
+        ExportName::Channel,
+        ExportName::TotalMessages,
+        ExportName::ChatEmotes,
+    ];
+
+    /// The token used on the command line and in config files.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExportName::Bttv => "bttv",
+            ExportName::Ffz => "ffz",
+            ExportName::Twitch => "twitch",
+            ExportName::Hashtag => "hashtag",
+            ExportName::Command => "command",
+            ExportName::Chatter => "chatter",
+            ExportName::Channel => "channel",
+            ExportName::TotalMessages => "totalmessages",
+            ExportName::ChatEmotes => "chatemotes",
+        }
+    }
+
+    /// Every token in the order of [`ExportName::ALL`], for clap's possible values.
+    pub fn variants() -> [&'static str; 9] {
+        let mut variants = [""; 9];
+        for (variant, export) in variants.iter_mut().zip(ExportName::ALL.iter()) {
+            *variant = export.as_str();
+        }
+        variants
     }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+impl fmt::Display for ExportName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ExportName {
+    type Err = String;
+
+    /// Parse a token, ignoring case like the command line does.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ExportName::ALL
+            .iter()
+            .copied()
+            .find(|export| export.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("valid values: {}", ExportName::variants().join(", ")))
+    }
+}
+
+impl Serialize for ExportName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ExportName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Used unless [`ExportConfig::with_fetch_concurrency`] says otherwise.
+pub const DEFAULT_FETCH_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ExportConfig {
     bttv: bool,
     ffz: bool,
@@ -32,6 +150,22 @@ pub struct ExportConfig {
     chatter: bool,
     channel: bool,
     total_messages: bool,
+    chat_emotes: bool,
+    channels: Vec<String>,
+    fetch_concurrency: usize,
+    limit: Option<usize>,
+    top_channels_limit: Option<usize>,
+    emote_ids: bool,
+    last_message: bool,
+    amounts: AmountKind,
+    window: StatsWindow,
+    sanitize: Sanitize,
+    filters: Filters,
+    smoothing: Option<f64>,
+    label_keys: LabelKeys,
+    prefix: String,
+    separator: MetricSeparator,
+    on_failure: FailurePolicy,
 }
 
 impl ExportConfig {
@@ -45,157 +179,2292 @@ impl ExportConfig {
             chatter: true,
             channel: true,
             total_messages: true,
+            chat_emotes: true,
+            ..Self::default()
+        }
+    }
+
+    /// Export nothing, the same as [`ExportConfig::default`].
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Turn every export that is on off and the other way around, e.g. to export
+    /// everything except chatters. Everything but the exports is kept.
+    pub fn complement(mut self) -> Self {
+        for export in ExportName::ALL.iter() {
+            let flag = self.flag_mut(*export);
+            *flag = !*flag;
+        }
+        self
+    }
+
+    /// Turn a single export on or off, e.g. `ExportConfig::all().with_export(ExportName::Chatter, false)`.
+    pub fn with_export(mut self, export: ExportName, enabled: bool) -> Self {
+        *self.flag_mut(export) = enabled;
+        self
+    }
+
+    /// The exports that are turned on, in the order of [`ExportName::ALL`].
+    pub fn enabled(&self) -> Vec<ExportName> {
+        ExportName::ALL
+            .iter()
+            .copied()
+            .filter(|&export| self.is_enabled(export))
+            .collect()
+    }
+
+    pub fn is_enabled(&self, export: ExportName) -> bool {
+        match export {
+            ExportName::Bttv => self.bttv,
+            ExportName::Ffz => self.ffz,
+            ExportName::Twitch => self.twitch,
+            ExportName::Hashtag => self.hashtag,
+            ExportName::Command => self.command,
+            ExportName::Chatter => self.chatter,
+            ExportName::Channel => self.channel,
+            ExportName::TotalMessages => self.total_messages,
+            ExportName::ChatEmotes => self.chat_emotes,
+        }
+    }
+
+    fn flag_mut(&mut self, export: ExportName) -> &mut bool {
+        match export {
+            ExportName::Bttv => &mut self.bttv,
+            ExportName::Ffz => &mut self.ffz,
+            ExportName::Twitch => &mut self.twitch,
+            ExportName::Hashtag => &mut self.hashtag,
+            ExportName::Command => &mut self.command,
+            ExportName::Chatter => &mut self.chatter,
+            ExportName::Channel => &mut self.channel,
+            ExportName::TotalMessages => &mut self.total_messages,
+            ExportName::ChatEmotes => &mut self.chat_emotes,
+        }
+    }
+
+    /// Whether any export needs the stats endpoint of each channel.
+    pub fn needs_stats(&self) -> bool {
+        self.bttv
+            || self.ffz
+            || self.twitch
+            || self.hashtag
+            || self.command
+            || self.chatter
+            || self.total_messages
+    }
+
+    /// Export stats for these channels instead of only `global`.
+    ///
+    /// Every series carries a `channel` label. `global` covers all of twitch, so sums across
+    /// channels have to leave it out, e.g. `sum(sestats_total_messages{channel!="global"})`.
+    pub fn with_channels(mut self, channels: Vec<String>) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    pub fn channels(&self) -> &[String] {
+        &self.channels
+    }
+
+    /// Fetch the stats of at most this many channels at once. Values below 1 count as 1.
+    pub fn with_fetch_concurrency(mut self, fetch_concurrency: usize) -> Self {
+        self.fetch_concurrency = fetch_concurrency.max(1);
+        self
+    }
+
+    pub fn fetch_concurrency(&self) -> usize {
+        self.fetch_concurrency
+    }
+
+    /// Only export the `limit` most used chatters, hashtags, commands and emotes.
+    pub fn with_limit(mut self, limit: Option<usize>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Only export the `limit` channels with the most messages.
+    pub fn with_top_channels_limit(mut self, limit: Option<usize>) -> Self {
+        self.top_channels_limit = limit;
+        self
+    }
+
+    pub fn top_channels_limit(&self) -> Option<usize> {
+        self.top_channels_limit
+    }
+
+    /// Add the emote id as an `emote_id` label to emote series.
+    ///
+    /// Handy to link to emote images, but every renamed emote becomes a new series.
+    pub fn with_emote_ids(mut self, emote_ids: bool) -> Self {
+        self.emote_ids = emote_ids;
+        self
+    }
+
+    /// Export when each channel's user last wrote in chat, fetched with the chat emotes.
+    ///
+    /// This adds one series per channel, so it stays off unless asked for.
+    pub fn with_last_message(mut self, last_message: bool) -> Self {
+        self.last_message = last_message;
+        self
+    }
+
+    /// Export usage amounts as gauges or counters, see [`AmountKind`].
+    pub fn with_amounts(mut self, amounts: AmountKind) -> Self {
+        self.amounts = amounts;
+        self
+    }
+
+    pub fn amounts(&self) -> AmountKind {
+        self.amounts
+    }
+
+    /// Export the channel stats of `window` instead of all-time stats.
+    ///
+    /// Anything but all-time adds a `window` label to the channel stats series.
+    pub fn with_window(mut self, window: StatsWindow) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn window(&self) -> StatsWindow {
+        self.window
+    }
+
+    /// Clean up chatter, emote, hashtag and command names before they become label values.
+    ///
+    /// Entries whose cleaned up names match are exported as one with their amounts summed.
+    pub fn with_sanitize(mut self, sanitize: Sanitize) -> Self {
+        self.sanitize = sanitize;
+        self
+    }
+
+    pub fn sanitize(&self) -> Sanitize {
+        self.sanitize
+    }
+
+    /// Skip chatters, hashtags, commands, emotes and top channels by name.
+    ///
+    /// The names are matched after sanitizing. Totals and series counts still cover
+    /// every entry.
+    pub fn with_filters(mut self, filters: Filters) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Export an exponential moving average of the usage amounts instead of the raw
+    /// amounts, with `alpha` as the weight of the newest amount.
+    ///
+    /// The alpha is clamped to 0..=1, where 1 is no smoothing. Only gauge amounts
+    /// are smoothed, counters and deltas never are.
+    pub fn with_smoothing(mut self, alpha: Option<f64>) -> Self {
+        self.smoothing = alpha.map(|alpha| alpha.clamp(0.0, 1.0));
+        self
+    }
+
+    pub fn smoothing(&self) -> Option<f64> {
+        self.smoothing
+    }
+
+    /// Add the `window` label to `labels` of a channel stats series, if one is needed.
+    fn window_labels(&self, mut labels: Vec<Label>) -> Vec<Label> {
+        if self.window != StatsWindow::AllTime {
+            labels.push(Label::new("window", self.window.label()));
+        }
+        labels
+    }
+
+    pub fn with_label_keys(mut self, label_keys: LabelKeys) -> Self {
+        self.label_keys = label_keys;
+        self
+    }
+
+    /// Replace `sestats` at the start of every metric name.
+    pub fn with_prefix(mut self, prefix: String) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn with_separator(mut self, separator: MetricSeparator) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    pub fn separator(&self) -> MetricSeparator {
+        self.separator
+    }
+
+    pub fn with_failure_policy(mut self, on_failure: FailurePolicy) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+
+    pub fn failure_policy(&self) -> FailurePolicy {
+        self.on_failure
+    }
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            bttv: false,
+            ffz: false,
+            twitch: false,
+            hashtag: false,
+            command: false,
+            chatter: false,
+            channel: false,
+            total_messages: false,
+            chat_emotes: false,
+            channels: vec![String::from("global")],
+            fetch_concurrency: DEFAULT_FETCH_CONCURRENCY,
+            limit: None,
+            top_channels_limit: None,
+            emote_ids: false,
+            last_message: false,
+            amounts: AmountKind::default(),
+            window: StatsWindow::default(),
+            sanitize: Sanitize::default(),
+            filters: Filters::default(),
+            smoothing: None,
+            label_keys: LabelKeys::default(),
+            prefix: String::from(DEFAULT_PREFIX),
+            separator: MetricSeparator::default(),
+            on_failure: FailurePolicy::default(),
         }
     }
 }
 
 impl From<Vec<ExportName>> for ExportConfig {
     fn from(values: Vec<ExportName>) -> Self {
-        let mut config = Self::default();
-
-        for value in values {
-            match value {
-                ExportName::Bttv => config.bttv = true,
-                ExportName::Ffz => config.ffz = true,
-                ExportName::Twitch => config.twitch = true,
-                ExportName::Hashtag => config.hashtag = true,
-                ExportName::Command => config.command = true,
-                ExportName::Chatter => config.chatter = true,
-                ExportName::Channel => config.channel = true,
-                ExportName::TotalMessages => config.total_messages = true,
-            }
+        values.into_iter().fold(Self::default(), |config, value| {
+            config.with_export(value, true)
+        })
+    }
+}
+
+/// Set how old the stats of every fetched channel are.
+///
+/// Call it on a faster timer than the exports, the age only means something
+/// if it keeps growing while fetches fail.
+pub fn emit_data_age(config: &ExportConfig, state: &ExportState) {
+    for (channel, age) in state.data_ages() {
+        state.status_gauge(
+            Metric::DataAge.name(&config.prefix, config.separator),
+            age.as_secs_f64(),
+            vec![Label::new("channel", channel)],
+        );
+    }
+}
+
+/// A metric an export emits and the label keys of its series.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeriesSchema {
+    pub metric: Metric,
+    pub labels: Vec<String>,
+}
+
+impl SeriesSchema {
+    fn new(metric: Metric, labels: &[&str]) -> Self {
+        Self {
+            metric,
+            labels: labels.iter().map(|label| label.to_string()).collect(),
         }
+    }
+}
+
+impl ExportConfig {
+    /// The series `export` emits with this config, in the order `export_stats` emits them.
+    ///
+    /// Label keys and opt-in labels like `emote_id` follow the config.
+    pub fn schema(&self, export: ExportName) -> Vec<SeriesSchema> {
+        let keys = &self.label_keys;
+        let series_count = SeriesSchema::new(Metric::SeriesCount, &["category", "channel"]);
+
+        let mut schema = match export {
+            ExportName::Bttv | ExportName::Ffz | ExportName::Twitch => {
+                let mut emote =
+                    SeriesSchema::new(Metric::Emote, &["channel", "provider", &keys.emote]);
+                if self.emote_ids {
+                    emote.labels.push(String::from("emote_id"));
+                }
+
+                vec![
+                    series_count,
+                    SeriesSchema::new(Metric::EmoteTotal, &["channel", "provider"]),
+                    emote,
+                ]
+            }
+            ExportName::Hashtag => vec![
+                series_count,
+                SeriesSchema::new(Metric::Hashtag, &["channel", &keys.hashtag]),
+            ],
+            ExportName::Command => vec![
+                series_count,
+                SeriesSchema::new(Metric::Command, &["channel", &keys.command]),
+            ],
+            ExportName::Chatter => vec![
+                series_count,
+                SeriesSchema::new(Metric::ChatterTotal, &["channel"]),
+                SeriesSchema::new(Metric::Chatter, &["channel", &keys.chatter]),
+            ],
+            ExportName::TotalMessages => {
+                vec![SeriesSchema::new(Metric::TotalMessages, &["channel"])]
+            }
+            // these do not come from the channel stats
+            ExportName::Channel => {
+                return vec![
+                    SeriesSchema::new(Metric::SeriesCount, &["category"]),
+                    SeriesSchema::new(Metric::Channel, &[&keys.channel]),
+                ]
+            }
+            ExportName::ChatEmotes => {
+                let mut schema = Vec::new();
+                if self.last_message {
+                    schema.push(SeriesSchema::new(
+                        Metric::UserLastMessage,
+                        &["channel", "username"],
+                    ));
+                }
+
+                let mut emote = SeriesSchema::new(
+                    Metric::ChatEmote,
+                    &[
+                        "channel",
+                        "provider",
+                        "scope",
+                        &keys.emote,
+                        "width",
+                        "height",
+                        "gif",
+                    ],
+                );
+                if self.emote_ids {
+                    emote.labels.push(String::from("emote_id"));
+                }
+                schema.push(emote);
+                return schema;
+            }
+        };
 
-        config
+        for series in &mut schema {
+            if self.window != StatsWindow::AllTime {
+                series.labels.push(String::from("window"));
+            }
+            if self.amounts == AmountKind::Delta {
+                series.metric = series.metric.delta().unwrap_or(series.metric);
+            }
+        }
+        schema
     }
 }
 
-fn drain_to_gauge<'a, I, L, ValueF, LabelF>(
-    name: &'static str,
+/// Set one gauge called `name` per entry of `data`, remembering it in `state`.
+///
+/// ```
+/// use metrics::Label;
+/// use se_stats_exporter::{drain_to_gauge, state::ExportState};
+///
+/// let state = ExportState::new();
+/// let chatters = [("forsen", 3), ("xqcow", 5)];
+///
+/// drain_to_gauge(
+///     &state,
+///     String::from("sestats.chatter"),
+///     chatters.iter(),
+///     |(_, amount): &&(&str, u64)| *amount as f64,
+///     |(name, _): &&(&str, u64)| vec![Label::new("name", name.to_string())],
+/// );
+/// ```
+pub fn drain_to_gauge<I, ValueF, LabelF>(
+    state: &ExportState,
+    name: String,
     data: I,
     value_f: ValueF,
     label_f: LabelF,
 ) where
     I: IntoIterator,
     ValueF: Fn(&I::Item) -> f64,
-    LabelF: Fn(&I::Item) -> L,
-    L: IntoLabels,
+    LabelF: Fn(&I::Item) -> Vec<Label>,
 {
     data.into_iter()
-        .for_each(|entry| gauge!(name, value_f(&entry), label_f(&entry)));
+        .for_each(|entry| state.gauge(name.clone(), value_f(&entry), label_f(&entry)));
 }
 
-#[instrument(skip(client))]
-pub async fn export_stats(config: &ExportConfig, client: &ApiClient) {
-    let stats = match client.get_stats("global").await {
-        Err(e) => {
-            error!("Could not get stats from stats.streamelements.com: {}", e);
-            return;
+/// Like [`drain_to_gauge`], but spreads the entries over the rayon thread pool.
+///
+/// Without the `parallel` feature the entries are drained in order on the calling thread.
+///
+/// ```
+/// use metrics::Label;
+/// use se_stats_exporter::{drain_to_gauge_par, state::ExportState};
+///
+/// let state = ExportState::new();
+/// let hashtags = vec![("#forsen", 3), ("#xqc", 5)];
+///
+/// drain_to_gauge_par(
+///     &state,
+///     String::from("sestats.hashtag"),
+///     hashtags,
+///     |(_, amount): &(&str, u64)| *amount as f64,
+///     |(hashtag, _): &(&str, u64)| vec![Label::new("hashtag", hashtag.to_string())],
+/// );
+/// ```
+pub fn drain_to_gauge_par<I, ValueF, LabelF>(
+    state: &ExportState,
+    name: String,
+    data: I,
+    value_f: ValueF,
+    label_f: LabelF,
+) where
+    I: IntoEntries,
+    ValueF: Fn(&I::Item) -> f64 + Sync + Send,
+    LabelF: Fn(&I::Item) -> Vec<Label> + Sync + Send,
+{
+    data.into_par_iter()
+        .for_each(|entry| state.gauge(name.clone(), value_f(&entry), label_f(&entry)));
+}
+
+/// Like [`drain_to_gauge_par`], but follows the configured [`AmountKind`] and smoothing.
+fn drain_amounts<I, ValueF, LabelF>(
+    config: &ExportConfig,
+    state: &ExportState,
+    metric: Metric,
+    data: I,
+    value_f: ValueF,
+    label_f: LabelF,
+) where
+    I: IntoEntries,
+    ValueF: Fn(&I::Item) -> u64 + Sync + Send,
+    LabelF: Fn(&I::Item) -> Vec<Label> + Sync + Send,
+{
+    let name = metric.name(&config.prefix, config.separator);
+
+    match (config.amounts, config.smoothing) {
+        (AmountKind::Gauge, None) => {
+            drain_to_gauge_par(state, name, data, |entry| value_f(entry) as f64, label_f)
+        }
+        (AmountKind::Gauge, Some(alpha)) => data.into_par_iter().for_each(|entry| {
+            state.smoothed_gauge(name.clone(), value_f(&entry) as f64, label_f(&entry), alpha)
+        }),
+        (AmountKind::Counter, _) => data
+            .into_par_iter()
+            .for_each(|entry| state.counter_total(name.clone(), value_f(&entry), label_f(&entry))),
+        (AmountKind::Delta, _) => {
+            let name = metric
+                .delta()
+                .expect("only amounts are drained")
+                .name(&config.prefix, config.separator);
+            data.into_par_iter()
+                .for_each(|entry| state.delta_gauge(name.clone(), value_f(&entry), label_f(&entry)))
+        }
+    }
+}
+
+/// The `limit` entries with the highest amount, or all of them without a limit.
+fn top_entries<'a, T, I, F>(entries: I, limit: Option<usize>, amount: F) -> Vec<&'a T>
+where
+    I: IntoIterator<Item = &'a T>,
+    F: Fn(&T) -> u64,
+{
+    let mut entries: Vec<&T> = entries.into_iter().collect();
+
+    if let Some(limit) = limit {
+        // the api returns them sorted, but nothing promises that
+        entries.sort_by_key(|entry| Reverse(amount(entry)));
+        entries.truncate(limit);
+    }
+
+    entries
+}
+
+/// Time spent fetching from the api and emitting metrics during one cycle.
+#[derive(Debug, Default)]
+struct PhaseDurations {
+    fetch: Duration,
+    emit: Duration,
+}
+
+impl PhaseDurations {
+    async fn fetch<F: Future>(&mut self, fetch: F) -> F::Output {
+        let start = Instant::now();
+        let output = fetch.await;
+        self.fetch += start.elapsed();
+        output
+    }
+
+    fn emit<R>(&mut self, emit: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let output = emit();
+        self.emit += start.elapsed();
+        output
+    }
+}
+
+/// Fetch and export everything enabled in `config`.
+///
+/// A failed fetch does not stop the others. Every failure gets logged and the
+/// first one is returned once the cycle is done.
+///
+/// Everything logged during the cycle is in a span with its `cycle` id, and in a
+/// child span with the `channel` where it belongs to one.
+#[instrument(skip_all, fields(cycle = state.next_cycle()))]
+pub async fn export_stats(
+    config: &ExportConfig,
+    client: &ApiClient,
+    state: &ExportState,
+) -> Result<(), stats_api::Error> {
+    if config.enabled().is_empty() {
+        debug!("Nothing to export, not fetching anything");
+        return Ok(());
+    }
+
+    debug!("Exporting stats to Prometheus");
+
+    let mut top_channels_durations = PhaseDurations::default();
+
+    // every channel gets its own durations so they can be fetched concurrently
+    let channels = stream::iter(config.channels.iter())
+        .map(|channel| async move {
+            let mut durations = PhaseDurations::default();
+            let result = export_channel(config, client, state, channel, &mut durations).await;
+            (result, durations)
+        })
+        .buffer_unordered(config.fetch_concurrency)
+        .collect::<Vec<_>>();
+    // the top channels do not depend on the channel stats, so fetch them meanwhile
+    let top_channels = async {
+        if config.channel {
+            Some(
+                top_channels_durations
+                    .fetch(
+                        client
+                            .get_top_channels()
+                            .instrument(debug_span!("fetch", endpoint = "top_channels")),
+                    )
+                    .await,
+            )
+        } else {
+            None
         }
-        Ok(s) => s,
     };
+    let (channels, top_channels) = tokio::join!(channels, top_channels);
 
-    let top_channels = match client.get_top_channels().await {
-        Err(e) => {
+    let mut result = Ok(());
+    let mut durations = top_channels_durations;
+    for (channel_result, channel_durations) in channels {
+        result = result.and(channel_result);
+        durations.fetch += channel_durations.fetch;
+        durations.emit += channel_durations.emit;
+    }
+
+    match top_channels {
+        None => {}
+        Some(Err(e)) => {
             error!(
                 "Could not get top channels from stats.streamelements.com: {}",
                 e
             );
-            return;
+            count_fetch_error(config, state, "top_channels", &e);
+            let name = Metric::Channel.name(&config.prefix, config.separator);
+            state.on_failure(config.on_failure, |key| key.name().to_string() == name);
+            result = result.and(Err(e));
+        }
+        Some(Ok(top_channels)) => {
+            durations.emit(|| emit_top_channels(config, state, &top_channels))
+        }
+    }
+
+    state.finish_cycle();
+
+    let name = Metric::ExportDuration.name(&config.prefix, config.separator);
+    state.status_gauge(
+        name.clone(),
+        durations.fetch.as_secs_f64(),
+        vec![Label::new("phase", "fetch")],
+    );
+    state.status_gauge(
+        name,
+        durations.emit.as_secs_f64(),
+        vec![Label::new("phase", "emit")],
+    );
+
+    // set it again so a recorder that drops idle gauges keeps it
+    state.status_gauge(
+        Metric::BuildInfo.name(&config.prefix, config.separator),
+        1.0,
+        names::build_info(),
+    );
+    state.status_gauge(
+        Metric::Up.name(&config.prefix, config.separator),
+        if result.is_ok() { 1.0 } else { 0.0 },
+        vec![],
+    );
+    if result.is_ok() {
+        state.status_gauge(
+            Metric::LastSuccess.name(&config.prefix, config.separator),
+            Utc::now().timestamp() as f64,
+            vec![],
+        );
+    }
+
+    debug!("Finished exporting stats");
+
+    result
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CycleError {
+    #[error(transparent)]
+    Fetch(#[from] stats_api::Error),
+
+    #[error("The export cycle panicked: {0}")]
+    Panic(String),
+}
+
+/// Like [`export_stats`], but a panic during the cycle becomes [`CycleError::Panic`].
+///
+/// The panic is logged, counted and marks the exporter as down, then the caller
+/// can keep going with the next cycle.
+pub async fn export_stats_guarded(
+    config: &ExportConfig,
+    client: &ApiClient,
+    state: &ExportState,
+) -> Result<(), CycleError> {
+    // nothing is reused after a panic except the state, and a half updated state
+    // only means some series are stale until the next cycle
+    match AssertUnwindSafe(export_stats(config, client, state))
+        .catch_unwind()
+        .await
+    {
+        Ok(result) => Ok(result?),
+        Err(panic) => {
+            let message = panic_message(&*panic);
+            error!("Export cycle panicked: {}", message);
+            // the sink might be what panicked
+            let reported = panic::catch_unwind(AssertUnwindSafe(|| {
+                state.count(
+                    Metric::ExportPanics.name(&config.prefix, config.separator),
+                    1,
+                    vec![],
+                );
+                state.status_gauge(
+                    Metric::Up.name(&config.prefix, config.separator),
+                    0.0,
+                    vec![],
+                );
+            }));
+            if reported.is_err() {
+                error!("Could not report the panic, the metric sink panicked too");
+            }
+            Err(CycleError::Panic(message))
+        }
+    }
+}
+
+/// Run one export cycle and yield every value it emits, for custom pipelines.
+///
+/// The stream ends once the cycle is done. Failures are not returned, the `up` gauge
+/// in the samples tells whether everything was fetched. Use a [`ChannelSink`] with
+/// your own [`ExportState`] to keep state like counter totals across cycles.
+pub fn export_to_channel<'a>(
+    config: &'a ExportConfig,
+    client: &'a ApiClient,
+) -> impl Stream<Item = MetricSample> + 'a {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let export = async move {
+        let state = ExportState::with_sink(Arc::new(ChannelSink::new(sender)));
+        // errors are already logged and reported through up
+        let _ = export_stats_guarded(config, client, &state).await;
+        // dropping the state closes the channel
+        None
+    };
+    let samples = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|sample| (sample, receiver))
+    });
+
+    stream::select(export.into_stream().filter_map(future::ready), samples)
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => panic
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| String::from("unknown panic payload")),
+    }
+}
+
+fn count_fetch_error(
+    config: &ExportConfig,
+    state: &ExportState,
+    endpoint: &'static str,
+    error: &stats_api::Error,
+) {
+    state.count(
+        Metric::FetchErrors.name(&config.prefix, config.separator),
+        1,
+        vec![
+            Label::new("endpoint", endpoint),
+            Label::new("kind", error.kind()),
+        ],
+    );
+}
+
+#[instrument(skip(config, client, state, durations), fields(channel = %channel))]
+async fn export_channel(
+    config: &ExportConfig,
+    client: &ApiClient,
+    state: &ExportState,
+    channel: &str,
+    durations: &mut PhaseDurations,
+) -> Result<(), stats_api::Error> {
+    if config.needs_stats() {
+        export_channel_stats(config, client, state, channel, durations).await?;
+    }
+
+    if config.chat_emotes {
+        export_chat_emotes(config, client, state, channel, durations).await?;
+    }
+
+    Ok(())
+}
+
+async fn export_channel_stats(
+    config: &ExportConfig,
+    client: &ApiClient,
+    state: &ExportState,
+    channel: &str,
+    durations: &mut PhaseDurations,
+) -> Result<(), stats_api::Error> {
+    let stats = match durations
+        .fetch(
+            client
+                .get_windowed_stats(channel, config.window)
+                .instrument(debug_span!("fetch", endpoint = "stats")),
+        )
+        .await
+    {
+        Err(e) => {
+            error!(
+                "Could not get stats for {} from stats.streamelements.com: {}",
+                channel, e
+            );
+            count_fetch_error(config, state, "stats", &e);
+            state.count(
+                Metric::ChannelErrors.name(&config.prefix, config.separator),
+                1,
+                vec![Label::new("channel", channel.to_string())],
+            );
+            let top_channels = Metric::Channel.name(&config.prefix, config.separator);
+            state.on_failure(config.on_failure, |key| {
+                key.name().to_string() != top_channels
+                    && key
+                        .labels()
+                        .any(|label| label.key() == "channel" && label.value() == channel)
+            });
+            return Err(e);
         }
         Ok(s) => s,
     };
 
-    debug!("Exporting stats to Prometheus");
+    state.status_gauge(
+        Metric::ChannelLastSuccess.name(&config.prefix, config.separator),
+        Utc::now().timestamp() as f64,
+        vec![Label::new("channel", channel.to_string())],
+    );
+    state.record_fetch(channel);
+
+    if let Err(e) = stats.validate() {
+        warn!(
+            "Stats for {} from stats.streamelements.com look corrupt: {}",
+            channel, e
+        );
+    }
+
+    durations.emit(|| emit_stats(config, state, channel, &stats));
 
+    Ok(())
+}
+
+fn emit_stats(config: &ExportConfig, state: &ExportState, channel: &str, stats: &ChatStats) {
     if config.total_messages {
-        gauge!("sestats.total-messages", stats.total_messages as f64);
+        state.gauge(
+            Metric::TotalMessages.name(&config.prefix, config.separator),
+            stats.total_messages as f64,
+            config.window_labels(vec![Label::new("channel", channel.to_string())]),
+        );
     }
 
     if config.chatter {
-        // stats.chatters.into_par_iter().for_each(|chatter| {
-        //     gauge!(
-        //         "sestats.chatter",
-        //         chatter.amount as f64,
-        //         &[("name", chatter.name.to_string()),]
-        //     )
-        // });
-        drain_to_gauge(
-            "sestats.chatter",
-            stats.chatters.to_vec(),
-            |chatter: &ChatterStats| chatter.amount as f64,
-            |chatter: &ChatterStats| vec![Label::new("name", chatter.name.to_string())],
+        emit_series_count(
+            config,
+            state,
+            Some(channel),
+            "chatter",
+            stats.chatters.len(),
+        );
+        state.gauge(
+            Metric::ChatterTotal.name(&config.prefix, config.separator),
+            stats
+                .chatters
+                .iter()
+                .map(|chatter| chatter.amount as f64)
+                .sum(),
+            config.window_labels(vec![Label::new("channel", channel.to_string())]),
+        );
+
+        let chatters = sanitized(
+            config,
+            state,
+            channel,
+            "chatter",
+            &config.filters.chatter,
+            &stats.chatters,
+            |chatter| &chatter.name,
+            |chatter| chatter.amount,
+        );
+        drain_amounts(
+            config,
+            state,
+            Metric::Chatter,
+            top_entries(&chatters, config.limit, |chatter| chatter.amount),
+            |chatter: &&Sanitized<ChatterStats>| chatter.amount,
+            |chatter: &&Sanitized<ChatterStats>| {
+                config.window_labels(vec![
+                    Label::new("channel", channel.to_string()),
+                    Label::new(config.label_keys.chatter.clone(), chatter.name.to_string()),
+                ])
+            },
         )
     }
 
     if config.hashtag {
-        stats.hashtags.into_par_iter().for_each(|hashtag| {
-            gauge!(
-                "sestats.hashtag",
-                hashtag.amount as f64,
-                &[("hashtag", hashtag.hashtag.to_string()),]
-            )
-        });
+        emit_series_count(
+            config,
+            state,
+            Some(channel),
+            "hashtag",
+            stats.hashtags.len(),
+        );
+        let hashtags = sanitized(
+            config,
+            state,
+            channel,
+            "hashtag",
+            &config.filters.hashtag,
+            &stats.hashtags,
+            |hashtag| &hashtag.hashtag,
+            |hashtag| hashtag.amount,
+        );
+        drain_amounts(
+            config,
+            state,
+            Metric::Hashtag,
+            top_entries(&hashtags, config.limit, |hashtag| hashtag.amount),
+            |hashtag: &&Sanitized<HashtagStats>| hashtag.amount,
+            |hashtag: &&Sanitized<HashtagStats>| {
+                config.window_labels(vec![
+                    Label::new("channel", channel.to_string()),
+                    Label::new(config.label_keys.hashtag.clone(), hashtag.name.to_string()),
+                ])
+            },
+        );
     }
 
     if config.command {
-        stats.commands.into_par_iter().for_each(|command| {
-            gauge!(
-                "sestats.hashtag",
-                command.amount as f64,
-                &[("command", command.command.to_string()),]
-            )
-        });
+        emit_series_count(
+            config,
+            state,
+            Some(channel),
+            "command",
+            stats.commands.len(),
+        );
+        let commands = sanitized(
+            config,
+            state,
+            channel,
+            "command",
+            &config.filters.command,
+            &stats.commands,
+            |command| &command.command,
+            |command| command.amount,
+        );
+        drain_amounts(
+            config,
+            state,
+            Metric::Command,
+            top_entries(&commands, config.limit, |command| command.amount),
+            |command: &&Sanitized<CommandStats>| command.amount,
+            |command: &&Sanitized<CommandStats>| {
+                config.window_labels(vec![
+                    Label::new("channel", channel.to_string()),
+                    Label::new(config.label_keys.command.clone(), command.name.to_string()),
+                ])
+            },
+        );
     }
 
     if config.bttv {
-        stats.bttv_emotes.into_par_iter().for_each(|emote| {
-            gauge!(
-                "sestats.emote",
-                emote.amount as f64,
-                &[
-                    ("provider", String::from("bttv")),
-                    ("emote", emote.emote.to_string()),
-                ]
-            )
-        });
+        emit_emotes(config, state, channel, "bttv", &stats.bttv_emotes);
     }
 
     if config.ffz {
-        stats.ffz_emotes.into_par_iter().for_each(|emote| {
-            gauge!(
-                "sestats.emote",
-                emote.amount as f64,
-                &[
-                    ("provider", String::from("ffz")),
-                    ("emote", emote.emote.to_string()),
-                ]
-            )
-        });
+        emit_emotes(config, state, channel, "ffz", &stats.ffz_emotes);
     }
 
     if config.twitch {
-        stats.twitch_emotes.into_par_iter().for_each(|emote| {
-            gauge!(
-                "sestats.emote",
-                emote.amount as f64,
-                &[
-                    ("provider", String::from("twitch")),
-                    ("emote", emote.emote.to_string()),
-                ]
-            )
-        });
+        emit_emotes(config, state, channel, "twitch", &stats.twitch_emotes);
+    }
+}
+
+/// Emit the top emotes of one provider and their total.
+fn emit_emotes(
+    config: &ExportConfig,
+    state: &ExportState,
+    channel: &str,
+    provider: &'static str,
+    emotes: &[EmoteStats],
+) {
+    emit_series_count(config, state, Some(channel), provider, emotes.len());
+
+    // the total covers every emote, not only the top ones
+    state.gauge(
+        Metric::EmoteTotal.name(&config.prefix, config.separator),
+        emotes.iter().map(|emote| emote.amount as f64).sum(),
+        config.window_labels(vec![
+            Label::new("channel", channel.to_string()),
+            Label::new("provider", provider),
+        ]),
+    );
+
+    let emotes = sanitized(
+        config,
+        state,
+        channel,
+        provider,
+        &config.filters.emote,
+        emotes,
+        |emote| &emote.emote,
+        |emote| emote.amount,
+    );
+    drain_amounts(
+        config,
+        state,
+        Metric::Emote,
+        top_entries(&emotes, config.limit, |emote| emote.amount),
+        |emote: &&Sanitized<EmoteStats>| emote.amount,
+        |emote: &&Sanitized<EmoteStats>| {
+            let mut labels = vec![
+                Label::new("channel", channel.to_string()),
+                Label::new("provider", provider),
+                Label::new(config.label_keys.emote.clone(), emote.name.to_string()),
+            ];
+            // merged emotes keep the id of the first one
+            if config.emote_ids {
+                labels.push(Label::new("emote_id", emote.entry.id.to_string()));
+            }
+            config.window_labels(labels)
+        },
+    );
+}
+
+/// Sanitize the names of `entries` as configured, counting the entries that got merged,
+/// and drop the ones `filter` does not allow.
+// channel and category only label the collision counter, bundling them would not help
+#[allow(clippy::too_many_arguments)]
+fn sanitized<'a, T, NameF, AmountF>(
+    config: &ExportConfig,
+    state: &ExportState,
+    channel: &str,
+    category: &'static str,
+    filter: &NameFilter,
+    entries: &'a [T],
+    name_f: NameF,
+    amount_f: AmountF,
+) -> Vec<Sanitized<'a, T>>
+where
+    NameF: Fn(&'a T) -> &'a str,
+    AmountF: Fn(&T) -> u64,
+{
+    let (mut entries, collisions) = sanitize_entries(config.sanitize, entries, name_f, amount_f);
+
+    if collisions > 0 {
+        debug!(
+            "Merged {} {} entries of {} with colliding names",
+            collisions, category, channel
+        );
+        state.count(
+            Metric::LabelCollisions.name(&config.prefix, config.separator),
+            collisions as u64,
+            vec![
+                Label::new("channel", channel.to_string()),
+                Label::new("category", category),
+            ],
+        );
     }
 
+    if !filter.is_empty() {
+        entries.retain(|entry| filter.allows(&entry.name));
+    }
+    entries
+}
+
+/// Record how many entries the api returned for `category`, before `--limit` applies.
+fn emit_series_count(
+    config: &ExportConfig,
+    state: &ExportState,
+    channel: Option<&str>,
+    category: &'static str,
+    len: usize,
+) {
+    let mut labels = vec![Label::new("category", category)];
+    // only the top channels are counted without a channel, they have no window
+    if let Some(channel) = channel {
+        labels.push(Label::new("channel", channel.to_string()));
+        labels = config.window_labels(labels);
+    }
+
+    state.gauge(
+        Metric::SeriesCount.name(&config.prefix, config.separator),
+        len as f64,
+        labels,
+    );
+}
+
+fn emit_top_channels(config: &ExportConfig, state: &ExportState, top_channels: &[Channel]) {
     if config.channel {
-        top_channels.into_par_iter().for_each(|channel| {
-            gauge!(
-                "sestats.channel",
-                channel.messages as f64,
-                &[("channel", channel.channel.to_string())]
-            )
+        emit_series_count(config, state, None, "channel", top_channels.len());
+
+        drain_to_gauge_par(
+            state,
+            Metric::Channel.name(&config.prefix, config.separator),
+            top_entries(
+                top_channels
+                    .iter()
+                    .filter(|channel| config.filters.channel.allows(&channel.channel)),
+                config.top_channels_limit,
+                |channel| channel.messages,
+            ),
+            |channel: &&Channel| channel.messages as f64,
+            |channel: &&Channel| {
+                vec![Label::new(
+                    config.label_keys.channel.clone(),
+                    channel.channel.to_string(),
+                )]
+            },
+        );
+    }
+}
+
+async fn export_chat_emotes(
+    config: &ExportConfig,
+    client: &ApiClient,
+    state: &ExportState,
+    channel: &str,
+    durations: &mut PhaseDurations,
+) -> Result<(), stats_api::Error> {
+    let chat_emotes = client
+        .get_chat_emotes(channel)
+        .instrument(debug_span!("fetch", endpoint = "chat_emotes"));
+    match durations.fetch(chat_emotes).await {
+        Err(e) => {
+            error!(
+                "Could not get chat emotes from stats.streamelements.com: {}",
+                e
+            );
+            count_fetch_error(config, state, "chat_emotes", &e);
+            state.count(
+                Metric::ChannelErrors.name(&config.prefix, config.separator),
+                1,
+                vec![Label::new("channel", channel.to_string())],
+            );
+            let chat_emote = Metric::ChatEmote.name(&config.prefix, config.separator);
+            let last_message = Metric::UserLastMessage.name(&config.prefix, config.separator);
+            state.on_failure(config.on_failure, |key| {
+                let name = key.name().to_string();
+                (name == chat_emote || name == last_message)
+                    && key
+                        .labels()
+                        .any(|label| label.key() == "channel" && label.value() == channel)
+            });
+            return Err(e);
+        }
+        Ok(chat_emotes) => durations.emit(|| {
+            if config.last_message {
+                emit_last_message(config, state, channel, &chat_emotes);
+            }
+
+            let emotes = &chat_emotes.emotes;
+            let all = vec![
+                ("bttv", "global", &emotes.bttv_global_emotes),
+                ("bttv", "channel", &emotes.bttv_channel_emotes),
+                ("ffz", "global", &emotes.ffz_global_emotes),
+                ("ffz", "channel", &emotes.ffz_channel_emotes),
+            ]
+            .into_par_iter()
+            .flat_map_iter(|(provider, scope, emotes)| {
+                emotes.values().map(move |emote| (provider, scope, emote))
+            })
+            .filter(|(_, _, emote)| config.filters.emote.allows(&emote.name));
+
+            drain_to_gauge_par(
+                state,
+                Metric::ChatEmote.name(&config.prefix, config.separator),
+                all,
+                |_| 1.0,
+                |(provider, scope, emote): &(&'static str, &'static str, &Emote)| {
+                    let mut labels = vec![
+                        Label::new("channel", channel.to_string()),
+                        Label::new("provider", *provider),
+                        Label::new("scope", *scope),
+                        Label::new(config.label_keys.emote.clone(), emote.name.to_string()),
+                        Label::new("width", emote.width.to_string()),
+                        Label::new("height", emote.height.to_string()),
+                        Label::new("gif", emote.gif.to_string()),
+                    ];
+                    if config.emote_ids {
+                        labels.push(Label::new("emote_id", emote.id.to_string()));
+                    }
+                    labels
+                },
+            );
+        }),
+    }
+
+    Ok(())
+}
+
+/// Set the unix time of the last message of the user behind `channel`.
+///
+/// The api sends the unix epoch for users that never wrote, those are skipped
+/// like missing timestamps instead of claiming a message from 1970.
+fn emit_last_message(
+    config: &ExportConfig,
+    state: &ExportState,
+    channel: &str,
+    chat_emotes: &ChatEmotes,
+) {
+    let timestamp = match chat_emotes.last_message {
+        Some(last_message) if last_message.timestamp() > 0 => last_message.timestamp(),
+        _ => return,
+    };
+
+    state.gauge(
+        Metric::UserLastMessage.name(&config.prefix, config.separator),
+        timestamp as f64,
+        vec![
+            Label::new("channel", channel.to_string()),
+            Label::new("username", chat_emotes.username.to_string()),
+        ],
+    );
+}
+
+/// How often [`run_ws_export`] refreshes the connected gauge.
+const WS_CONNECTED_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many entries of each kind and channel the live feed counts without a `--limit`.
+///
+/// The feed has no top list that bounds them, every new chatter would be a new series.
+const MAX_LIVE_ENTRIES: usize = 1000;
+
+/// The entries of each channel and kind of change the live feed counts so far.
+#[derive(Debug, Default)]
+struct LiveEntries(HashMap<(String, &'static str), HashSet<String>>);
+
+/// Subscribe to the live feed of every channel and count each change until the socket closes.
+///
+/// Watch `global` for the changes of every channel. The counters are seeded with the alltime
+/// totals from `api` first, so they start where the api is instead of at zero. The client
+/// is borrowed so the caller can close it afterwards, or when it stops waiting for this
+/// future.
+///
+/// Names are sanitized and filtered like in [`export_stats`]. Only the first `--limit`
+/// entries of each kind and channel are counted, the seeded ones first, or
+/// [`MAX_LIVE_ENTRIES`] without a limit.
+#[instrument(skip(api, state, client))]
+pub async fn run_ws_export(
+    config: &ExportConfig,
+    api: &ApiClient,
+    state: &ExportState,
+    client: &mut WsClient,
+    channels: &[String],
+) -> Result<(), stats_ws::Error> {
+    for channel in channels {
+        client.subscribe_to_stats(channel).await?;
+    }
+    info!("Subscribed to live stats");
+
+    // seeding after subscribing counts a change that reaches both twice, seeding first
+    // would lose every change in between instead
+    let mut entries = LiveEntries::default();
+    for channel in channels {
+        seed_live_counters(config, api, state, &mut entries, channel).await;
+    }
+
+    let mut dropped = 0;
+    let connected = Metric::WsConnected.name(&config.prefix, config.separator);
+    let mut connected_check = tokio::time::interval(WS_CONNECTED_INTERVAL);
+
+    loop {
+        // a silent disconnect sends no messages, so the gauge needs its own tick
+        let message = tokio::select! {
+            message = client.recv_message() => message,
+            _ = connected_check.tick() => {
+                state.status_gauge(
+                    connected.clone(),
+                    if client.is_connected() { 1.0 } else { 0.0 },
+                    vec![],
+                );
+                continue;
+            }
+        };
+
+        if !matches!(message, Err(stats_ws::Error::RecvIncomingMessageError)) {
+            state.count(
+                Metric::WsMessages.name(&config.prefix, config.separator),
+                1,
+                vec![],
+            );
+        }
+        let (channel, changes) = match message {
+            Err(stats_ws::Error::RecvIncomingMessageError) => break,
+            // one bad message should not end the feed, e.g. after a schema change
+            Err(e @ stats_ws::Error::ParseMessageError(_))
+            | Err(e @ stats_ws::Error::UnknownRoomError(_)) => {
+                warn!("Skipping live stats message: {}", e);
+                state.count(
+                    Metric::WsParseErrors.name(&config.prefix, config.separator),
+                    1,
+                    vec![],
+                );
+                continue;
+            }
+            result => result?,
+        };
+
+        let now_dropped = client.dropped_messages();
+        if now_dropped > dropped {
+            state.count(
+                Metric::WsDropped.name(&config.prefix, config.separator),
+                now_dropped - dropped,
+                vec![],
+            );
+            dropped = now_dropped;
+        }
+
+        for change in changes {
+            state.count(
+                Metric::WsChanges.name(&config.prefix, config.separator),
+                1,
+                vec![Label::new("type", change.kind())],
+            );
+            count_live_change(config, state, &mut entries, &channel, change);
+        }
+    }
+
+    Ok(())
+}
+
+/// Count the alltime totals of `channel` once, as if they had come from the feed.
+///
+/// A failed fetch is only logged, the counters then start at zero.
+async fn seed_live_counters(
+    config: &ExportConfig,
+    api: &ApiClient,
+    state: &ExportState,
+    entries: &mut LiveEntries,
+    channel: &str,
+) {
+    let stats = match api.get_stats(channel).await {
+        Ok(stats) => stats,
+        Err(e) => {
+            warn!("Could not seed the live counters of {}: {}", channel, e);
+            count_fetch_error(config, state, "stats", &e);
+            return;
+        }
+    };
+
+    let chatters = stats
+        .chatters
+        .iter()
+        .map(|chatter| StatsChangeMessage::Chatters {
+            key: chatter.name.clone(),
+            amount: chatter.amount,
+        });
+    let hashtags = stats
+        .hashtags
+        .iter()
+        .map(|hashtag| StatsChangeMessage::Hashtags {
+            key: hashtag.hashtag.clone(),
+            amount: hashtag.amount,
+        });
+    let commands = stats
+        .commands
+        .iter()
+        .map(|command| StatsChangeMessage::Commands {
+            key: command.command.clone(),
+            amount: command.amount,
+        });
+    let emotes = stats
+        .twitch_emotes
+        .iter()
+        .chain(stats.bttv_emotes.iter())
+        .chain(stats.ffz_emotes.iter())
+        .map(|emote| StatsChangeMessage::Emotes {
+            key: emote.emote.clone(),
+            amount: emote.amount,
+        });
+
+    for change in chatters.chain(hashtags).chain(commands).chain(emotes) {
+        count_live_change(config, state, entries, channel, change);
+    }
+    debug!("Seeded the live counters of {}", channel);
+}
+
+/// Add the amount of one change to its live counter, unless its entry is filtered or capped.
+fn count_live_change(
+    config: &ExportConfig,
+    state: &ExportState,
+    entries: &mut LiveEntries,
+    channel: &str,
+    change: StatsChangeMessage,
+) {
+    let kind = change.kind();
+    let (metric, label_key, filter, key, amount) = match change {
+        StatsChangeMessage::Chatters { key, amount } => (
+            Metric::LiveChatterMessages,
+            &config.label_keys.chatter,
+            &config.filters.chatter,
+            key,
+            amount,
+        ),
+        StatsChangeMessage::Emotes { key, amount } => (
+            Metric::LiveEmoteUses,
+            &config.label_keys.emote,
+            &config.filters.emote,
+            key,
+            amount,
+        ),
+        StatsChangeMessage::Hashtags { key, amount } => (
+            Metric::LiveHashtagUses,
+            &config.label_keys.hashtag,
+            &config.filters.hashtag,
+            key,
+            amount,
+        ),
+        StatsChangeMessage::Commands { key, amount } => (
+            Metric::LiveCommandUses,
+            &config.label_keys.command,
+            &config.filters.command,
+            key,
+            amount,
+        ),
+        StatsChangeMessage::Unknown => return,
+    };
+
+    let key = config.sanitize.apply(&key);
+    if !filter.allows(&key) {
+        return;
+    }
+    let seen = entries.0.entry((channel.to_string(), kind)).or_default();
+    if !seen.contains(key.as_ref()) {
+        if seen.len() >= config.limit.unwrap_or(MAX_LIVE_ENTRIES) {
+            return;
+        }
+        seen.insert(key.to_string());
+    }
+
+    state.count(
+        metric.name(&config.prefix, config.separator),
+        amount,
+        vec![
+            Label::new("channel", channel.to_string()),
+            Label::new(label_key.clone(), key.into_owned()),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        drain_to_gauge, drain_to_gauge_par, emit_last_message, emit_stats, emit_top_channels,
+        export_stats, export_stats_guarded, export_to_channel, run_ws_export,
+        state::{ExportState, FailurePolicy},
+        CycleError, ExportConfig, ExportName,
+    };
+    use crate::filter::Filters;
+    use crate::names::{AmountKind, Metric};
+    use crate::sanitize::Sanitize;
+    use crate::sink::{MetricSample, MetricSink, RecordingSink};
+    use crate::stats_api::{
+        mock::MockApi, ApiClient, Channel, ChatEmotes, ChatStats, EmoteStats, Error, StatsWindow,
+    };
+    use crate::stats_ws::WsClient;
+    use futures_util::{SinkExt, StreamExt};
+    use metrics::{KeyData, Label};
+    use std::{collections::HashSet, sync::Arc, time::Duration};
+    use tokio::{net::TcpListener, time};
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+    const GLOBAL_STATS: &str = include_str!("../tests/fixtures/global_stats.json");
+    const TOP_CHANNELS: &str = include_str!("../tests/fixtures/top_channels.json");
+
+    /// The emitted keys without the series_count canaries.
+    fn entry_keys(state: &ExportState) -> Vec<KeyData> {
+        state
+            .emitted()
+            .into_iter()
+            .filter(|key| key.name().to_string() != "sestats.series_count")
+            .collect()
+    }
+
+    #[test]
+    fn commands_are_exported_as_commands() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let config = ExportConfig::from(vec![ExportName::Command]);
+        let state = ExportState::new();
+
+        emit_stats(&config, &state, "global", &stats);
+
+        let keys = entry_keys(&state);
+        assert_eq!(keys.len(), stats.commands.len());
+        assert!(keys
+            .iter()
+            .all(|key| key.name().to_string() == "sestats.command"));
+        assert!(keys.iter().any(|key| key
+            .labels()
+            .any(|label| label.key() == "command" && label.value() == "!uptime")));
+    }
+
+    #[test]
+    fn with_export_toggles_one_export() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let config = ExportConfig::from(vec![ExportName::Command, ExportName::Hashtag])
+            .with_export(ExportName::Hashtag, false);
+        let state = ExportState::new();
+
+        emit_stats(&config, &state, "global", &stats);
+
+        assert!(entry_keys(&state)
+            .iter()
+            .all(|key| key.name().to_string() == "sestats.command"));
+    }
+
+    #[test]
+    fn complement_flips_every_export() {
+        assert_eq!(ExportConfig::all().complement(), ExportConfig::none());
+        assert_eq!(
+            ExportConfig::from(vec![ExportName::Chatter]).complement(),
+            ExportConfig::all().with_export(ExportName::Chatter, false)
+        );
+    }
+
+    #[test]
+    fn enabled_round_trips() {
+        let config = ExportConfig::from(vec![ExportName::Twitch, ExportName::Bttv]);
+
+        assert_eq!(config.enabled(), [ExportName::Bttv, ExportName::Twitch]);
+        assert_eq!(ExportConfig::from(config.enabled()), config);
+        assert_eq!(ExportConfig::all().enabled(), ExportName::ALL);
+    }
+
+    #[test]
+    fn export_names_round_trip() {
+        for &export in ExportName::ALL.iter() {
+            assert_eq!(export.to_string().parse::<ExportName>(), Ok(export));
+            assert_eq!(
+                serde_json::from_value::<ExportName>(serde_json::to_value(export).unwrap())
+                    .unwrap(),
+                export
+            );
+        }
+        assert_eq!("TotalMessages".parse(), Ok(ExportName::TotalMessages));
+        assert!("emotes".parse::<ExportName>().is_err());
+    }
+
+    #[test]
+    fn totals_sum_every_entry() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let config = ExportConfig::from(vec![ExportName::Bttv]).with_limit(Some(1));
+        let state = ExportState::new();
+
+        emit_stats(&config, &state, "global", &stats);
+
+        let totals: Vec<_> = state
+            .emitted()
+            .into_iter()
+            .filter(|key| key.name().to_string() == "sestats.emote_total")
+            .collect();
+        assert_eq!(totals.len(), 1);
+        assert!(totals[0]
+            .labels()
+            .any(|label| label.key() == "provider" && label.value() == "bttv"));
+    }
+
+    #[test]
+    fn drain_emits_one_series_per_entry() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let state = ExportState::new();
+        let label = |emote: &&EmoteStats| vec![Label::new("emote", emote.emote.to_string())];
+
+        drain_to_gauge(
+            &state,
+            String::from("sestats.bttv"),
+            stats.bttv_emotes.iter(),
+            |emote| emote.amount as f64,
+            label,
+        );
+        drain_to_gauge_par(
+            &state,
+            String::from("sestats.ffz"),
+            &*stats.ffz_emotes,
+            |emote| emote.amount as f64,
+            label,
+        );
+
+        assert_eq!(
+            state.emitted().len(),
+            stats.bttv_emotes.len() + stats.ffz_emotes.len()
+        );
+    }
+
+    #[test]
+    fn series_counts_follow_the_flags() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let config = ExportConfig::from(vec![ExportName::Chatter, ExportName::Ffz]);
+        let state = ExportState::new();
+
+        emit_stats(&config, &state, "global", &stats);
+
+        let mut categories: Vec<_> = state
+            .emitted()
+            .iter()
+            .filter(|key| key.name().to_string() == "sestats.series_count")
+            .flat_map(|key| {
+                key.labels()
+                    .filter(|label| label.key() == "category")
+                    .map(|label| label.value().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        categories.sort();
+        assert_eq!(categories, ["chatter", "ffz"]);
+    }
+
+    #[test]
+    fn top_channels_limit() {
+        let top_channels = [
+            Channel {
+                channel: "forsen".into(),
+                messages: 1,
+            },
+            Channel {
+                channel: "xqcow".into(),
+                messages: 2,
+            },
+        ];
+        let config = ExportConfig::from(vec![ExportName::Channel]);
+
+        let state = ExportState::new();
+        emit_top_channels(
+            &config.clone().with_top_channels_limit(Some(1)),
+            &state,
+            &top_channels,
+        );
+        let keys = entry_keys(&state);
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0]
+            .labels()
+            .any(|label| label.key() == "channel" && label.value() == "xqcow"));
+
+        let state = ExportState::new();
+        emit_top_channels(
+            &config.with_top_channels_limit(Some(10)),
+            &state,
+            &top_channels,
+        );
+        assert_eq!(entry_keys(&state).len(), 2);
+    }
+
+    #[test]
+    fn emote_ids_are_opt_in() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let has_ids = |config: ExportConfig| {
+            let state = ExportState::new();
+            emit_stats(&config, &state, "global", &stats);
+            entry_keys(&state)
+                .iter()
+                .filter(|key| key.name().to_string() == "sestats.emote")
+                .all(|key| key.labels().any(|label| label.key() == "emote_id"))
+        };
+
+        assert!(!has_ids(ExportConfig::from(vec![ExportName::Bttv])));
+        assert!(has_ids(
+            ExportConfig::from(vec![ExportName::Bttv]).with_emote_ids(true)
+        ));
+    }
+
+    #[test]
+    fn emotes_carry_their_amounts() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let config = ExportConfig::from(vec![ExportName::Ffz]);
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+
+        emit_stats(&config, &state, "global", &stats);
+
+        let emotes: Vec<_> = sink
+            .gauges()
+            .into_iter()
+            .filter(|(key, _)| key.name().to_string() == "sestats.emote")
+            .collect();
+        assert_eq!(emotes.len(), stats.ffz_emotes.len());
+        for emote in stats.ffz_emotes.iter() {
+            assert!(emotes.iter().any(|(key, value)| {
+                *value == emote.amount as f64
+                    && key
+                        .labels()
+                        .any(|label| label.key() == "emote" && label.value() == emote.emote)
+            }));
+        }
+    }
+
+    #[test]
+    fn last_message_skips_the_epoch() {
+        let config = ExportConfig::default();
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+        let chat_emotes = |last_message: &str| -> ChatEmotes {
+            serde_json::from_str(&format!(
+                r#"{{{}"username": "forsen", "emotes": {{}}}}"#,
+                last_message
+            ))
+            .unwrap()
+        };
+
+        emit_last_message(&config, &state, "forsen", &chat_emotes(""));
+        emit_last_message(
+            &config,
+            &state,
+            "forsen",
+            &chat_emotes(r#""lastMessage": "1970-01-01T00:00:00.000Z", "#),
+        );
+        assert!(sink.gauges().is_empty());
+
+        emit_last_message(
+            &config,
+            &state,
+            "forsen",
+            &chat_emotes(r#""lastMessage": "2021-03-01T12:00:00.000Z", "#),
+        );
+        let gauges = sink.gauges();
+        assert_eq!(gauges.len(), 1);
+        assert_eq!(
+            gauges[0].0.name().to_string(),
+            "sestats.user_last_message_timestamp_seconds"
+        );
+        assert_eq!(gauges[0].1, 1614600000.0);
+    }
+
+    #[test]
+    fn counter_amounts_only_add_the_growth() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let config =
+            ExportConfig::from(vec![ExportName::Chatter]).with_amounts(AmountKind::Counter);
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+
+        emit_stats(&config, &state, "global", &stats);
+        emit_stats(&config, &state, "global", &stats);
+
+        let counters = sink.counters();
+        assert_eq!(counters.len(), stats.chatters.len() * 2);
+        let (first, second) = counters.split_at(stats.chatters.len());
+        assert_eq!(
+            first.iter().map(|(_, value)| value).sum::<u64>(),
+            stats
+                .chatters
+                .iter()
+                .map(|chatter| chatter.amount)
+                .sum::<u64>()
+        );
+        assert!(second.iter().all(|(_, value)| *value == 0));
+        assert!(sink
+            .gauges()
+            .iter()
+            .all(|(key, _)| key.name().to_string() != "sestats.chatter"));
+    }
+
+    #[test]
+    fn delta_amounts_replace_the_amounts() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let config = ExportConfig::from(vec![ExportName::Chatter]).with_amounts(AmountKind::Delta);
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+
+        emit_stats(&config, &state, "global", &stats);
+        emit_stats(&config, &state, "global", &stats);
+
+        let gauges = sink.gauges();
+        let deltas: Vec<_> = gauges
+            .iter()
+            .filter(|(key, _)| key.name().to_string() == "sestats.chatter_delta")
+            .collect();
+        assert_eq!(deltas.len(), stats.chatters.len() * 2);
+        assert!(deltas.iter().all(|(_, value)| *value == 0.0));
+        assert!(gauges
+            .iter()
+            .all(|(key, _)| key.name().to_string() != "sestats.chatter"));
+    }
+
+    #[test]
+    fn sanitized_names_are_merged() {
+        let stats: ChatStats = serde_json::from_str(
+            r#"{
+                "channel": "global",
+                "totalMessages": 6,
+                "chatters": [
+                    {"name": "forsen", "amount": 3},
+                    {"name": "forsen\n", "amount": 2},
+                    {"name": "\txqcow", "amount": 1}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let config = ExportConfig::from(vec![ExportName::Chatter]).with_sanitize(Sanitize::Strip);
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+
+        emit_stats(&config, &state, "global", &stats);
+
+        let chatters: Vec<_> = sink
+            .gauges()
+            .into_iter()
+            .filter(|(key, _)| key.name().to_string() == "sestats.chatter")
+            .map(|(key, value)| {
+                let name = key
+                    .labels()
+                    .find(|label| label.key() == "name")
+                    .unwrap()
+                    .value()
+                    .to_string();
+                (name, value)
+            })
+            .collect();
+        assert_eq!(chatters.len(), 2);
+        assert!(chatters.contains(&(String::from("forsen"), 5.0)));
+        assert!(chatters.contains(&(String::from("xqcow"), 1.0)));
+    }
+
+    /// The metric names and label keys of everything `state` emitted, without values.
+    fn emitted_schema(state: &ExportState) -> HashSet<(String, Vec<String>)> {
+        state
+            .emitted()
+            .into_iter()
+            .map(|key| {
+                let labels = key.labels().map(|label| label.key().to_string()).collect();
+                (key.name().to_string(), labels)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn schema_matches_what_is_emitted() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let top_channels: Vec<Channel> = serde_json::from_str(TOP_CHANNELS).unwrap();
+
+        // counters are not remembered as emitted, so only the gauge kinds can be compared
+        for &amounts in [AmountKind::Gauge, AmountKind::Delta].iter() {
+            for &export in ExportName::ALL.iter() {
+                // chat emotes have their own endpoint, emit_stats does not cover them
+                if export == ExportName::ChatEmotes {
+                    continue;
+                }
+
+                let config = ExportConfig::from(vec![export])
+                    .with_emote_ids(true)
+                    .with_window(StatsWindow::Last7d)
+                    .with_amounts(amounts);
+                let state = ExportState::new();
+                emit_stats(&config, &state, "global", &stats);
+                emit_top_channels(&config, &state, &top_channels);
+
+                let expected: HashSet<_> = config
+                    .schema(export)
+                    .into_iter()
+                    .map(|series| {
+                        (
+                            series.metric.name(config.prefix(), config.separator()),
+                            series.labels,
+                        )
+                    })
+                    .collect();
+                assert_eq!(emitted_schema(&state), expected, "{} {}", export, amounts);
+            }
+        }
+    }
+
+    #[test]
+    fn limit_keeps_the_most_used_entries() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let config = ExportConfig::from(vec![ExportName::Command]).with_limit(Some(1));
+        let state = ExportState::new();
+
+        emit_stats(&config, &state, "global", &stats);
+
+        let keys = entry_keys(&state);
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0]
+            .labels()
+            .any(|label| label.key() == "command" && label.value() == "!uptime"));
+    }
+
+    #[test]
+    fn denied_entries_do_not_count_towards_the_limit() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let mut filters = Filters::default();
+        filters.deny("command", "!uptime").unwrap();
+        let config = ExportConfig::from(vec![ExportName::Command])
+            .with_limit(Some(1))
+            .with_filters(filters);
+        let state = ExportState::new();
+
+        emit_stats(&config, &state, "global", &stats);
+
+        let keys = entry_keys(&state);
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0]
+            .labels()
+            .any(|label| label.key() == "command" && label.value() != "!uptime"));
+    }
+
+    #[test]
+    fn series_carry_their_channel() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let config = ExportConfig::all();
+        let state = ExportState::new();
+
+        emit_stats(&config, &state, "forsen", &stats);
+
+        assert!(state.emitted().iter().all(|key| key
+            .labels()
+            .any(|label| label.key() == "channel" && label.value() == "forsen")));
+    }
+
+    #[test]
+    fn total_messages_are_kept_apart_per_channel() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let config = ExportConfig::all();
+        let state = ExportState::new();
+
+        emit_stats(&config, &state, "global", &stats);
+        emit_stats(&config, &state, "forsen", &stats);
+
+        let total_messages = Metric::TotalMessages.name(&config.prefix, config.separator);
+        let mut channels: Vec<String> = state
+            .emitted()
+            .iter()
+            .filter(|key| key.name().to_string() == total_messages)
+            .flat_map(|key| {
+                key.labels()
+                    .filter(|label| label.key() == "channel")
+                    .map(|label| label.value().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        channels.sort();
+        assert_eq!(channels, ["forsen", "global"]);
+    }
+
+    #[tokio::test]
+    async fn failed_channels_do_not_stop_the_others() {
+        let api = MockApi::start();
+        let client = ApiClient::new()
+            .unwrap()
+            .with_base_url(api.url())
+            .with_retries(0, Duration::from_millis(1));
+        let config = ExportConfig::from(vec![ExportName::Chatter])
+            .with_channels(vec![
+                String::from("unavailable"),
+                String::from("global"),
+                String::from("forsen"),
+            ])
+            .with_fetch_concurrency(2);
+
+        let result = export_stats(&config, &client, &ExportState::new()).await;
+
+        assert!(matches!(result, Err(Error::HttpStatus { .. })));
+        let requests = api.requests();
+        assert!(requests.contains(&String::from("/chatstats/global/stats")));
+        assert!(requests.contains(&String::from("/chatstats/forsen/stats")));
+    }
+
+    #[tokio::test]
+    async fn every_write_goes_through_the_sink() {
+        let api = MockApi::start();
+        let client = ApiClient::new()
+            .unwrap()
+            .with_base_url(api.url())
+            .with_retries(0, Duration::from_millis(1));
+        let config = ExportConfig::from(vec![ExportName::Chatter]);
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+
+        export_stats(
+            &config.clone().with_channels(vec![String::from("forsen")]),
+            &client,
+            &state,
+        )
+        .await
+        .unwrap();
+        export_stats(
+            &config.with_channels(vec![String::from("unavailable")]),
+            &client,
+            &state,
+        )
+        .await
+        .unwrap_err();
+
+        let gauges = sink.gauges();
+        let values = |name: &str| -> Vec<f64> {
+            gauges
+                .iter()
+                .filter(|(key, _)| key.name().to_string() == name)
+                .map(|(_, value)| *value)
+                .collect()
+        };
+        assert_eq!(values("sestats.up"), [1.0, 0.0]);
+        assert_eq!(values("sestats.last_success_timestamp_seconds").len(), 1);
+        assert_eq!(
+            values("sestats.channel_last_success_timestamp_seconds").len(),
+            1
+        );
+        assert_eq!(values("sestats.export_duration_seconds").len(), 4);
+        // the second cycle left out every chatter of forsen
+        let chatters = values("sestats.chatter");
+        let (first, second) = chatters.split_at(chatters.len() / 2);
+        assert!(first.iter().all(|value| *value > 0.0));
+        assert!(second.iter().all(|value| *value == 0.0));
+
+        let counters: Vec<String> = sink
+            .counters()
+            .into_iter()
+            .map(|(key, _)| key.name().to_string())
+            .collect();
+        assert_eq!(
+            counters,
+            ["sestats.fetch_errors_total", "sestats.channel_errors_total"]
+        );
+    }
+
+    #[tokio::test]
+    async fn export_to_channel_yields_every_sample() {
+        let api = MockApi::start();
+        let client = ApiClient::new().unwrap().with_base_url(api.url());
+        let config = ExportConfig::from(vec![ExportName::Chatter])
+            .with_channels(vec![String::from("forsen")]);
+
+        let samples: Vec<MetricSample> = export_to_channel(&config, &client).collect().await;
+
+        let gauges: Vec<(String, f64)> = samples
+            .iter()
+            .filter_map(|sample| match sample {
+                MetricSample::Gauge { key, value } => Some((key.name().to_string(), *value)),
+                MetricSample::Counter { .. } => None,
+            })
+            .collect();
+        assert!(gauges
+            .iter()
+            .any(|(name, value)| name == "sestats.chatter" && *value > 0.0));
+        assert!(gauges.contains(&(String::from("sestats.up"), 1.0)));
+        // nothing failed, so nothing was counted
+        assert!(samples
+            .iter()
+            .all(|sample| matches!(sample, MetricSample::Gauge { .. })));
+    }
+
+    #[tokio::test]
+    async fn chat_emote_failures_apply_the_failure_policy() {
+        let api = MockApi::start();
+        let client = ApiClient::new()
+            .unwrap()
+            .with_base_url(api.url())
+            .with_retries(0, Duration::from_millis(1));
+        let down = client
+            .clone()
+            .with_base_url(api.url().join("down/").unwrap());
+        let config = ExportConfig::from(vec![ExportName::ChatEmotes])
+            .with_channels(vec![String::from("forsen")])
+            .with_last_message(true)
+            .with_failure_policy(FailurePolicy::Zero);
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+
+        export_stats(&config, &client, &state).await.unwrap();
+        let emitted = sink.gauges().len();
+        export_stats(&config, &down, &state).await.unwrap_err();
+
+        assert_eq!(api.requests()[0], "/chatstats/forsen/emotes");
+        let zeroed: Vec<String> = sink.gauges()[emitted..]
+            .iter()
+            .filter(|(_, value)| *value == 0.0)
+            .map(|(key, _)| key.name().to_string())
+            .collect();
+        // the four emotes of the fixture and the last message, but not up
+        assert_eq!(
+            zeroed
+                .iter()
+                .filter(|name| *name == "sestats.chat_emote")
+                .count(),
+            4
+        );
+        assert!(zeroed.contains(&String::from("sestats.user_last_message_timestamp_seconds")));
+        let counters: Vec<(String, Vec<String>)> = sink
+            .counters()
+            .into_iter()
+            .map(|(key, _)| {
+                (
+                    key.name().to_string(),
+                    key.labels()
+                        .map(|label| label.value().to_string())
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            counters,
+            [
+                (
+                    String::from("sestats.fetch_errors_total"),
+                    vec![String::from("chat_emotes"), String::from("http")]
+                ),
+                (
+                    String::from("sestats.channel_errors_total"),
+                    vec![String::from("forsen")]
+                ),
+            ]
+        );
+    }
+
+    /// Serve one stats message for forsen, then keep the connection open.
+    async fn serve_live_feed(message: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            // wait for the subscription
+            ws.next().await;
+            ws.send(Message::Text(message.to_string())).await.unwrap();
+            while ws.next().await.is_some() {}
         });
+
+        url
+    }
+
+    /// Run the live feed until `done` returns true for the counters seen so far.
+    async fn run_live_feed<F>(config: &ExportConfig, url: &str, done: F) -> Arc<RecordingSink>
+    where
+        F: Fn(&[(KeyData, u64)]) -> bool,
+    {
+        let api = MockApi::start();
+        let api_client = ApiClient::new().unwrap().with_base_url(api.url());
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+        let mut client = WsClient::connect(url).await.unwrap();
+
+        {
+            let feed = run_ws_export(config, &api_client, &state, &mut client, config.channels());
+            tokio::pin!(feed);
+            time::timeout(Duration::from_secs(5), async {
+                while !done(&sink.counters()) {
+                    tokio::select! {
+                        result = &mut feed => panic!("live feed stopped: {:?}", result),
+                        _ = time::sleep(Duration::from_millis(10)) => {}
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        }
+
+        client.close().await.unwrap();
+        sink
+    }
+
+    fn live_counts(counters: &[(KeyData, u64)], name: &str, entry: &str) -> Vec<u64> {
+        counters
+            .iter()
+            .filter(|(key, _)| {
+                key.name().to_string() == name
+                    && key
+                        .labels()
+                        .any(|label| label.key() != "channel" && label.value() == entry)
+            })
+            .map(|(_, value)| *value)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn live_counters_start_at_the_rest_totals() {
+        let url = serve_live_feed(
+            r#"{"id": "1", "event": "message", "destination": "twitchstats:forsen:stats", "data": [
+                {"type": "chatters", "key": "nightbot", "amount": 2},
+                {"type": "emotes", "key": "Kappa", "amount": 1}
+            ]}"#,
+        )
+        .await;
+        let config = ExportConfig::default().with_channels(vec![String::from("forsen")]);
+
+        let sink = run_live_feed(&config, &url, |counters| {
+            live_counts(counters, "sestats.live_emote_uses_total", "Kappa").len() == 2
+        })
+        .await;
+
+        let counters = sink.counters();
+        // the fixture total first, then the change from the feed on top
+        assert_eq!(
+            live_counts(&counters, "sestats.live_chatter_messages_total", "nightbot"),
+            [102934811, 2]
+        );
+        assert_eq!(
+            live_counts(&counters, "sestats.live_emote_uses_total", "Kappa"),
+            [1259762743, 1]
+        );
+        assert_eq!(
+            live_counts(&counters, "sestats.live_hashtag_uses_total", "#ad"),
+            [2185373]
+        );
+        assert!(counters
+            .iter()
+            .filter(|(key, _)| key.name().to_string().starts_with("sestats.live_"))
+            .all(|(key, _)| key
+                .labels()
+                .any(|label| label.key() == "channel" && label.value() == "forsen")));
+    }
+
+    #[tokio::test]
+    async fn live_changes_are_sanitized_filtered_and_capped() {
+        let url = serve_live_feed(
+            r#"{"id": "1", "event": "message", "destination": "twitchstats:forsen:stats", "data": [
+                {"type": "chatters", "key": " NightBot", "amount": 2},
+                {"type": "chatters", "key": "StreamElements", "amount": 1},
+                {"type": "chatters", "key": "forsen", "amount": 1},
+                {"type": "chatters", "key": "xqcow", "amount": 1},
+                {"type": "emotes", "key": "LUL", "amount": 1}
+            ]}"#,
+        )
+        .await;
+        let mut filters = Filters::default();
+        filters.deny("chatter", "streamelements").unwrap();
+        let config = ExportConfig::default()
+            .with_channels(vec![String::from("forsen")])
+            .with_sanitize(Sanitize::Lowercase)
+            .with_filters(filters)
+            .with_limit(Some(2));
+
+        let sink = run_live_feed(&config, &url, |counters| {
+            counters
+                .iter()
+                .filter(|(key, _)| key.name().to_string() == "sestats.ws_changes_total")
+                .count()
+                == 5
+        })
+        .await;
+
+        let counters = sink.counters();
+        let chatters = |entry| live_counts(&counters, "sestats.live_chatter_messages_total", entry);
+        let emotes = |entry| live_counts(&counters, "sestats.live_emote_uses_total", entry);
+        assert_eq!(chatters("nightbot"), [102934811, 2]);
+        assert!(chatters("streamelements").is_empty());
+        // the seed left room for one more chatter
+        assert_eq!(chatters("forsen"), [1]);
+        assert!(chatters("xqcow").is_empty());
+        assert_eq!(emotes("kappa"), [1259762743]);
+        assert_eq!(emotes("pogchamp"), [857113942]);
+        assert!(emotes("lul").is_empty());
     }
 
-    debug!("Finished exporting stats")
+    #[derive(Debug)]
+    struct PanickingSink;
+
+    impl MetricSink for PanickingSink {
+        fn gauge(&self, _key: &KeyData, _value: f64) {
+            panic!("sink broke");
+        }
+
+        fn counter(&self, _key: &KeyData, _value: u64) {
+            panic!("sink broke");
+        }
+    }
+
+    #[tokio::test]
+    async fn panics_end_the_cycle_but_not_the_loop() {
+        let api = MockApi::start();
+        let client = ApiClient::new().unwrap().with_base_url(api.url());
+        let config = ExportConfig::from(vec![ExportName::Chatter]);
+        let state = ExportState::with_sink(Arc::new(PanickingSink));
+
+        for _ in 0..2 {
+            let result = export_stats_guarded(&config, &client, &state).await;
+
+            assert!(matches!(result, Err(CycleError::Panic(message)) if message == "sink broke"));
+        }
+        assert_eq!(api.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn export_fetches_stats_and_top_channels() {
+        let api = MockApi::start();
+        let client = ApiClient::new().unwrap().with_base_url(api.url());
+        let config = ExportConfig::from(vec![ExportName::Channel, ExportName::Chatter]);
+
+        export_stats(&config, &client, &ExportState::new())
+            .await
+            .unwrap();
+
+        let mut requests = api.requests();
+        requests.sort();
+        assert_eq!(requests, ["/chatstats", "/chatstats/global/stats"]);
+    }
+
+    #[tokio::test]
+    async fn only_needed_endpoints_are_fetched() {
+        let api = MockApi::start();
+        let client = ApiClient::new().unwrap().with_base_url(api.url());
+
+        let config = ExportConfig::from(vec![ExportName::TotalMessages]);
+        export_stats(&config, &client, &ExportState::new())
+            .await
+            .unwrap();
+        assert_eq!(api.requests(), ["/chatstats/global/stats"]);
+
+        let config = ExportConfig::from(vec![ExportName::Channel]);
+        export_stats(&config, &client, &ExportState::new())
+            .await
+            .unwrap();
+        assert_eq!(api.requests()[1..], ["/chatstats"]);
+    }
+
+    #[tokio::test]
+    async fn exporting_nothing_fetches_nothing() {
+        let api = MockApi::start();
+        let client = ApiClient::new().unwrap().with_base_url(api.url());
+
+        export_stats(&ExportConfig::none(), &client, &ExportState::new())
+            .await
+            .unwrap();
+
+        assert!(api.requests().is_empty());
+    }
 }
@@ -0,0 +1,388 @@
+use clap::arg_enum;
+use metrics::{GaugeValue, Key, KeyData, Label};
+
+arg_enum! {
+    #[derive(PartialEq, Debug, Clone, Copy)]
+    pub enum MetricSeparator {
+        Dot,
+        Underscore,
+    }
+}
+
+// arg_enum! does not accept `#[default]` on variants
+#[allow(clippy::derivable_impls)]
+impl Default for MetricSeparator {
+    fn default() -> Self {
+        MetricSeparator::Dot
+    }
+}
+
+arg_enum! {
+    /// How the usage amounts of emotes, chatters, hashtags and commands are exported.
+    ///
+    /// - `Gauge` sets the amount the api returned. Entries that drop out of the top
+    ///   list go to 0, but `rate()` gets confused whenever the api resets its stats.
+    /// - `Counter` only adds the growth since the last export, so `rate()` and
+    ///   `increase()` work. Dropped entries keep their last value and the first
+    ///   export after a restart counts the whole amount.
+    /// - `Delta` sets gauges like `sestats_emote_delta` to the growth since the last
+    ///   export, clamped at 0 when the api resets. The first export sets 0.
+    #[derive(PartialEq, Debug, Clone, Copy)]
+    pub enum AmountKind {
+        Gauge,
+        Counter,
+        Delta,
+    }
+}
+
+// arg_enum! does not accept `#[default]` on variants
+#[allow(clippy::derivable_impls)]
+impl Default for AmountKind {
+    fn default() -> Self {
+        AmountKind::Gauge
+    }
+}
+
+/// Every metric the exporter emits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    Emote,
+    TotalMessages,
+    Chatter,
+    Channel,
+    Command,
+    Hashtag,
+    ChannelLastSuccess,
+    ChannelErrors,
+    ApiRequests,
+    ChatEmote,
+    LiveChatterMessages,
+    LiveEmoteUses,
+    LiveHashtagUses,
+    LiveCommandUses,
+    ExportDuration,
+    /// 1 after a cycle without errors, 0 after any failed fetch.
+    Up,
+    /// Unix time of the last cycle without errors, alert on e.g.
+    /// `time() - sestats_last_success_timestamp_seconds > 300`.
+    LastSuccess,
+    FetchErrors,
+    RateLimitRemaining,
+    EmoteTotal,
+    ChatterTotal,
+    SeriesCount,
+    WsDropped,
+    WsParseErrors,
+    UserLastMessage,
+    /// Always 1, the version lives in the labels.
+    BuildInfo,
+    WsMessages,
+    WsChanges,
+    /// 1 while the websocket is connected, 0 while it reconnects.
+    WsConnected,
+    /// Refreshed on its own timer, so it keeps growing while fetches fail.
+    DataAge,
+    LabelCollisions,
+    ExportPanics,
+    EmoteDelta,
+    ChatterDelta,
+    HashtagDelta,
+    CommandDelta,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 36] = [
+        Metric::Emote,
+        Metric::TotalMessages,
+        Metric::Chatter,
+        Metric::Channel,
+        Metric::Command,
+        Metric::Hashtag,
+        Metric::ChannelLastSuccess,
+        Metric::ChannelErrors,
+        Metric::ApiRequests,
+        Metric::ChatEmote,
+        Metric::LiveChatterMessages,
+        Metric::LiveEmoteUses,
+        Metric::LiveHashtagUses,
+        Metric::LiveCommandUses,
+        Metric::ExportDuration,
+        Metric::Up,
+        Metric::LastSuccess,
+        Metric::FetchErrors,
+        Metric::RateLimitRemaining,
+        Metric::EmoteTotal,
+        Metric::ChatterTotal,
+        Metric::SeriesCount,
+        Metric::WsDropped,
+        Metric::WsParseErrors,
+        Metric::UserLastMessage,
+        Metric::BuildInfo,
+        Metric::WsMessages,
+        Metric::WsChanges,
+        Metric::WsConnected,
+        Metric::DataAge,
+        Metric::LabelCollisions,
+        Metric::ExportPanics,
+        Metric::EmoteDelta,
+        Metric::ChatterDelta,
+        Metric::HashtagDelta,
+        Metric::CommandDelta,
+    ];
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Metric::Emote => "emote",
+            Metric::TotalMessages => "total-messages",
+            Metric::Chatter => "chatter",
+            Metric::Channel => "channel",
+            Metric::Command => "command",
+            Metric::Hashtag => "hashtag",
+            Metric::ChannelLastSuccess => "channel_last_success_timestamp_seconds",
+            Metric::ChannelErrors => "channel_errors_total",
+            Metric::ApiRequests => "api_requests_total",
+            Metric::ChatEmote => "chat_emote",
+            Metric::LiveChatterMessages => "live_chatter_messages_total",
+            Metric::LiveEmoteUses => "live_emote_uses_total",
+            Metric::LiveHashtagUses => "live_hashtag_uses_total",
+            Metric::LiveCommandUses => "live_command_uses_total",
+            Metric::ExportDuration => "export_duration_seconds",
+            Metric::Up => "up",
+            Metric::LastSuccess => "last_success_timestamp_seconds",
+            Metric::FetchErrors => "fetch_errors_total",
+            Metric::RateLimitRemaining => "ratelimit_remaining",
+            Metric::EmoteTotal => "emote_total",
+            Metric::ChatterTotal => "chatter_total",
+            Metric::SeriesCount => "series_count",
+            Metric::WsDropped => "ws_dropped_total",
+            Metric::WsParseErrors => "ws_parse_errors_total",
+            Metric::UserLastMessage => "user_last_message_timestamp_seconds",
+            Metric::BuildInfo => "build_info",
+            Metric::WsMessages => "ws_messages_received_total",
+            Metric::WsChanges => "ws_changes_total",
+            Metric::WsConnected => "ws_connected",
+            Metric::DataAge => "data_age_seconds",
+            Metric::LabelCollisions => "label_collisions_total",
+            Metric::ExportPanics => "export_panics_total",
+            Metric::EmoteDelta => "emote_delta",
+            Metric::ChatterDelta => "chatter_delta",
+            Metric::HashtagDelta => "hashtag_delta",
+            Metric::CommandDelta => "command_delta",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Metric::Emote => "top emotes",
+            Metric::TotalMessages => {
+                "total messages per channel, channel=\"global\" already counts all of twitch"
+            }
+            Metric::Chatter => "top chatters",
+            Metric::Channel => "top channels",
+            Metric::Command => "top commands",
+            Metric::Hashtag => "top hashtags",
+            Metric::ChannelLastSuccess => "unix time of the last successful fetch per channel",
+            Metric::ChannelErrors => "failed fetches per channel",
+            Metric::ApiRequests => "requests to the streamelements api by status class",
+            Metric::ChatEmote => "emotes enabled in chat with their metadata",
+            Metric::LiveChatterMessages => "messages per chatter seen on the websocket feed",
+            Metric::LiveEmoteUses => "emote uses seen on the websocket feed",
+            Metric::LiveHashtagUses => "hashtag uses seen on the websocket feed",
+            Metric::LiveCommandUses => "command uses seen on the websocket feed",
+            Metric::ExportDuration => "time the last export cycle spent per phase (fetch or emit)",
+            Metric::Up => "whether the last export cycle fetched everything",
+            Metric::LastSuccess => "unix time of the last export cycle without errors",
+            Metric::FetchErrors => "failed fetches by endpoint and error kind",
+            Metric::RateLimitRemaining => "requests left before the api rate limits us",
+            Metric::EmoteTotal => "summed uses of every emote in the response per provider",
+            Metric::ChatterTotal => "summed messages of every chatter in the response",
+            Metric::SeriesCount => "entries the api returned per category",
+            Metric::WsDropped => "websocket messages dropped because they were not read in time",
+            Metric::WsParseErrors => "websocket messages skipped because they could not be parsed",
+            Metric::UserLastMessage => "unix time of the last chat message of a user",
+            Metric::BuildInfo => "version, git commit and compiler of the running exporter",
+            Metric::WsMessages => "messages read from the websocket feed",
+            Metric::WsChanges => "changes in the websocket messages by type",
+            Metric::WsConnected => "whether the websocket feed is connected",
+            Metric::DataAge => "seconds since the stats of a channel were fetched",
+            Metric::LabelCollisions => {
+                "entries merged into another because their sanitized names matched"
+            }
+            Metric::ExportPanics => "export cycles that were cut short by a panic",
+            Metric::EmoteDelta => "emote uses since the last export",
+            Metric::ChatterDelta => "messages per chatter since the last export",
+            Metric::HashtagDelta => "hashtag uses since the last export",
+            Metric::CommandDelta => "command uses since the last export",
+        }
+    }
+
+    pub fn is_counter(self) -> bool {
+        matches!(
+            self,
+            Metric::ChannelErrors
+                | Metric::ApiRequests
+                | Metric::FetchErrors
+                | Metric::LiveChatterMessages
+                | Metric::LiveEmoteUses
+                | Metric::LiveHashtagUses
+                | Metric::LiveCommandUses
+                | Metric::WsDropped
+                | Metric::WsParseErrors
+                | Metric::WsMessages
+                | Metric::WsChanges
+                | Metric::LabelCollisions
+                | Metric::ExportPanics
+        )
+    }
+
+    /// Whether this is a usage amount that follows [`AmountKind`].
+    pub fn is_amount(self) -> bool {
+        matches!(
+            self,
+            Metric::Emote | Metric::Chatter | Metric::Hashtag | Metric::Command
+        )
+    }
+
+    /// The metric [`AmountKind::Delta`] exports instead of this amount.
+    pub fn delta(self) -> Option<Metric> {
+        match self {
+            Metric::Emote => Some(Metric::EmoteDelta),
+            Metric::Chatter => Some(Metric::ChatterDelta),
+            Metric::Hashtag => Some(Metric::HashtagDelta),
+            Metric::Command => Some(Metric::CommandDelta),
+            _ => None,
+        }
+    }
+
+    /// Build the internal metric name, e.g. `sestats.emote` or `sestats_emote`.
+    pub fn name(self, prefix: &str, separator: MetricSeparator) -> String {
+        match separator {
+            MetricSeparator::Dot => format!("{}.{}", prefix, self.suffix()),
+            MetricSeparator::Underscore => {
+                format!("{}_{}", prefix, self.suffix().replace('-', "_"))
+            }
+        }
+    }
+}
+
+/// Used when no prefix is configured.
+pub const DEFAULT_PREFIX: &str = "sestats";
+
+/// Whether `prefix` can start a Prometheus metric name.
+///
+/// Colons are allowed in metric names but reserved for recording rules.
+pub fn is_valid_prefix(prefix: &str) -> bool {
+    let mut chars = prefix.chars();
+
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !prefix.starts_with("__")
+}
+
+/// Register all metrics with the installed recorder so their descriptions get exported.
+pub fn register_metrics(prefix: &str, separator: MetricSeparator, amounts: AmountKind) {
+    if let Some(recorder) = metrics::try_recorder() {
+        for &metric in Metric::ALL.iter() {
+            let key = Key::Owned(KeyData::from_name(metric.name(prefix, separator)));
+
+            if metric.is_counter() || (metric.is_amount() && amounts == AmountKind::Counter) {
+                recorder.register_counter(key, None, Some(metric.description()));
+            } else {
+                recorder.register_gauge(key, None, Some(metric.description()));
+            }
+        }
+    }
+}
+
+/// The labels of [`Metric::BuildInfo`].
+pub fn build_info() -> Vec<Label> {
+    vec![
+        Label::new("version", env!("CARGO_PKG_VERSION")),
+        Label::new("git_sha", env!("SE_STATS_GIT_SHA")),
+        Label::new("rustc", env!("SE_STATS_RUSTC_VERSION")),
+    ]
+}
+
+/// Set the build info gauge at startup, it never changes while running.
+///
+/// [`export_stats`](crate::export_stats) sets it again every cycle.
+pub fn set_build_info(prefix: &str, separator: MetricSeparator) {
+    if let Some(recorder) = metrics::try_recorder() {
+        let key = Key::Owned(KeyData::from_parts(
+            Metric::BuildInfo.name(prefix, separator),
+            build_info(),
+        ));
+        recorder.update_gauge(key, GaugeValue::Absolute(1.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_info, is_valid_prefix, Metric, MetricSeparator, DEFAULT_PREFIX};
+    use metrics::{GaugeValue, Key, KeyData, Recorder};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    #[test]
+    fn internal_names() {
+        assert_eq!(
+            Metric::TotalMessages.name(DEFAULT_PREFIX, MetricSeparator::Dot),
+            "sestats.total-messages"
+        );
+        assert_eq!(
+            Metric::TotalMessages.name(DEFAULT_PREFIX, MetricSeparator::Underscore),
+            "sestats_total_messages"
+        );
+    }
+
+    #[test]
+    fn custom_prefix() {
+        assert_eq!(
+            Metric::Emote.name("streamelements", MetricSeparator::Dot),
+            "streamelements.emote"
+        );
+        assert!(is_valid_prefix("se_stats"));
+        assert!(!is_valid_prefix(""));
+        assert!(!is_valid_prefix("se-stats"));
+        assert!(!is_valid_prefix("se.stats"));
+        assert!(!is_valid_prefix("1stats"));
+        assert!(!is_valid_prefix("__stats"));
+    }
+
+    #[test]
+    fn build_info_has_the_crate_version() {
+        let labels = build_info();
+
+        assert!(labels
+            .iter()
+            .any(|label| label.key() == "version" && label.value() == env!("CARGO_PKG_VERSION")));
+        assert!(labels.iter().all(|label| !label.value().is_empty()));
+    }
+
+    #[test]
+    fn exposition_names_match_for_both_separators() {
+        for &separator in [MetricSeparator::Dot, MetricSeparator::Underscore].iter() {
+            let recorder = PrometheusBuilder::new().build();
+
+            for &metric in Metric::ALL.iter() {
+                recorder.update_gauge(
+                    Key::Owned(KeyData::from_name(metric.name(DEFAULT_PREFIX, separator))),
+                    GaugeValue::Absolute(1.0),
+                );
+            }
+
+            let output = recorder.handle().render();
+            for name in [
+                "sestats_emote 1",
+                "sestats_total_messages 1",
+                "sestats_chatter 1",
+                "sestats_channel 1",
+                "sestats_command 1",
+                "sestats_hashtag 1",
+            ]
+            .iter()
+            {
+                assert!(output.contains(name), "{} missing in {}", name, output);
+            }
+        }
+    }
+}
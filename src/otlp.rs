@@ -0,0 +1,256 @@
+use metrics::{GaugeValue, Key, KeyData, Recorder, Unit};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The OTLP/HTTP path metrics are posted to, relative to the collector.
+const METRICS_PATH: &str = "v1/metrics";
+
+/// `AGGREGATION_TEMPORALITY_CUMULATIVE`, counters only ever grow.
+const CUMULATIVE: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Point {
+    Counter(f64),
+    Gauge(f64),
+}
+
+/// A recorder that keeps the current values so [`OtlpExporter`] can push them.
+///
+/// Counters become cumulative monotonic sums, gauges stay gauges. Histograms are
+/// dropped, nothing in the exporter records any.
+#[derive(Debug)]
+pub struct OtlpRecorder {
+    values: Mutex<BTreeMap<KeyData, Point>>,
+    descriptions: Mutex<BTreeMap<String, &'static str>>,
+    start: SystemTime,
+}
+
+impl Default for OtlpRecorder {
+    fn default() -> Self {
+        Self {
+            values: Mutex::default(),
+            descriptions: Mutex::default(),
+            start: SystemTime::now(),
+        }
+    }
+}
+
+impl OtlpRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Render every value as an OTLP `ExportMetricsServiceRequest` in its json encoding.
+    pub fn render(&self) -> Value {
+        let now = unix_nanos(SystemTime::now());
+        let start = unix_nanos(self.start);
+        let descriptions = self.descriptions.lock().unwrap();
+
+        // one metric per name, the labels tell the data points apart
+        let mut metrics: BTreeMap<String, (bool, Vec<Value>)> = BTreeMap::new();
+        for (key, value) in self.values.lock().unwrap().iter() {
+            let attributes: Vec<Value> = key
+                .labels()
+                .map(|label| json!({"key": label.key(), "value": {"stringValue": label.value()}}))
+                .collect();
+            let (is_counter, point) = match *value {
+                Point::Counter(value) => (
+                    true,
+                    json!({
+                        "attributes": attributes,
+                        "startTimeUnixNano": start,
+                        "timeUnixNano": now,
+                        "asDouble": value,
+                    }),
+                ),
+                Point::Gauge(value) => (
+                    false,
+                    json!({"attributes": attributes, "timeUnixNano": now, "asDouble": value}),
+                ),
+            };
+
+            let entry = metrics
+                .entry(key.name().to_string())
+                .or_insert_with(|| (is_counter, Vec::new()));
+            entry.1.push(point);
+        }
+
+        let metrics: Vec<Value> = metrics
+            .into_iter()
+            .map(|(name, (is_counter, points))| {
+                let mut metric = json!({
+                    "name": name,
+                    "description": descriptions.get(&name).copied().unwrap_or_default(),
+                });
+                if is_counter {
+                    metric["sum"] = json!({
+                        "dataPoints": points,
+                        "aggregationTemporality": CUMULATIVE,
+                        "isMonotonic": true,
+                    });
+                } else {
+                    metric["gauge"] = json!({ "dataPoints": points });
+                }
+                metric
+            })
+            .collect();
+
+        json!({
+            "resourceMetrics": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": {"stringValue": env!("CARGO_PKG_NAME")},
+                    }],
+                },
+                "scopeMetrics": [{
+                    "scope": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                    "metrics": metrics,
+                }],
+            }],
+        })
+    }
+
+    fn describe(&self, key: &Key, description: Option<&'static str>) {
+        if let Some(description) = description {
+            self.descriptions
+                .lock()
+                .unwrap()
+                .insert(key.name().to_string(), description);
+        }
+    }
+}
+
+/// OTLP wants 64 bit integers as strings in json.
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+impl Recorder for OtlpRecorder {
+    fn register_counter(&self, key: Key, _unit: Option<Unit>, description: Option<&'static str>) {
+        self.describe(&key, description);
+    }
+
+    fn register_gauge(&self, key: Key, _unit: Option<Unit>, description: Option<&'static str>) {
+        self.describe(&key, description);
+    }
+
+    fn register_histogram(
+        &self,
+        _key: Key,
+        _unit: Option<Unit>,
+        _description: Option<&'static str>,
+    ) {
+    }
+
+    fn increment_counter(&self, key: Key, value: u64) {
+        let mut values = self.values.lock().unwrap();
+        let entry = values
+            .entry(key.into_owned())
+            .or_insert(Point::Counter(0.0));
+        if let Point::Counter(total) = entry {
+            *total += value as f64;
+        }
+    }
+
+    fn update_gauge(&self, key: Key, value: GaugeValue) {
+        let mut values = self.values.lock().unwrap();
+        let entry = values.entry(key.into_owned()).or_insert(Point::Gauge(0.0));
+        if let Point::Gauge(current) = entry {
+            *current = value.update_value(*current);
+        }
+    }
+
+    fn record_histogram(&self, _key: Key, _value: f64) {}
+}
+
+/// Pushes the values of an [`OtlpRecorder`] to an OTLP/HTTP collector.
+#[derive(Debug, Clone)]
+pub struct OtlpExporter {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl OtlpExporter {
+    /// `endpoint` is the collector itself, e.g. `http://localhost:4318`.
+    pub fn new(endpoint: &str, timeout: Duration) -> Result<Self, reqwest::Error> {
+        let client = reqwest::ClientBuilder::new().timeout(timeout).build()?;
+
+        Ok(OtlpExporter {
+            client,
+            url: metrics_url(endpoint),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn push(&self, recorder: &OtlpRecorder) -> Result<(), reqwest::Error> {
+        self.client
+            .post(&self.url)
+            .json(&recorder.render())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+fn metrics_url(endpoint: &str) -> String {
+    format!("{}/{}", endpoint.trim_end_matches('/'), METRICS_PATH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{metrics_url, OtlpRecorder};
+    use metrics::{GaugeValue, Key, KeyData, Label, Recorder};
+
+    #[test]
+    fn renders_sums_and_gauges() {
+        let recorder = OtlpRecorder::new();
+        let emote = Key::Owned(KeyData::from_parts(
+            "sestats_emote",
+            vec![Label::new("emote", "Kappa")],
+        ));
+        let requests = Key::Owned(KeyData::from_name("sestats_api_requests_total"));
+
+        recorder.register_gauge(emote.clone(), None, Some("top emotes"));
+        recorder.update_gauge(emote, GaugeValue::Absolute(42.0));
+        recorder.increment_counter(requests.clone(), 2);
+        recorder.increment_counter(requests, 3);
+
+        let body = recorder.render();
+        let metrics = &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+
+        assert_eq!(metrics[0]["name"], "sestats_api_requests_total");
+        assert_eq!(metrics[0]["sum"]["isMonotonic"], true);
+        assert_eq!(metrics[0]["sum"]["dataPoints"][0]["asDouble"], 5.0);
+
+        assert_eq!(metrics[1]["name"], "sestats_emote");
+        assert_eq!(metrics[1]["description"], "top emotes");
+        let point = &metrics[1]["gauge"]["dataPoints"][0];
+        assert_eq!(point["asDouble"], 42.0);
+        assert_eq!(point["attributes"][0]["key"], "emote");
+        assert_eq!(point["attributes"][0]["value"]["stringValue"], "Kappa");
+    }
+
+    #[test]
+    fn metrics_url_ignores_trailing_slash() {
+        assert_eq!(
+            metrics_url("http://localhost:4318/"),
+            "http://localhost:4318/v1/metrics"
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! The iterators behind [`drain_to_gauge_par`](crate::drain_to_gauge_par) and the emit loops.
+//!
+//! With the `parallel` feature, on by default, these are rayon's parallel iterators. Without it
+//! they are plain iterators under the same names and rayon is not compiled in at all.
+
+#[cfg(feature = "parallel")]
+pub use rayon::iter::{IntoParallelIterator as IntoEntries, ParallelIterator as Entries};
+
+#[cfg(not(feature = "parallel"))]
+pub use sequential::{Entries, IntoEntries};
+
+#[cfg(not(feature = "parallel"))]
+mod sequential {
+    use std::iter::FlatMap;
+
+    /// [`IntoIterator`] with the method name of rayon's `IntoParallelIterator`.
+    pub trait IntoEntries {
+        type Item;
+        type Iter: Entries<Item = Self::Item>;
+
+        fn into_par_iter(self) -> Self::Iter;
+    }
+
+    impl<I: IntoIterator> IntoEntries for I {
+        type Item = I::Item;
+        type Iter = I::IntoIter;
+
+        fn into_par_iter(self) -> Self::Iter {
+            self.into_iter()
+        }
+    }
+
+    /// The methods of rayon's `ParallelIterator` that [`Iterator`] calls differently.
+    pub trait Entries: Iterator + Sized {
+        fn flat_map_iter<U, F>(self, f: F) -> FlatMap<Self, U, F>
+        where
+            U: IntoIterator,
+            F: FnMut(Self::Item) -> U,
+        {
+            self.flat_map(f)
+        }
+    }
+
+    impl<I: Iterator> Entries for I {}
+}
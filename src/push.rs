@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+/// The job label every push is grouped under.
+pub const JOB: &str = "se-stats-exporter";
+
+/// Pushes rendered metrics to a Prometheus Pushgateway.
+#[derive(Debug, Clone)]
+pub struct PushGateway {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl PushGateway {
+    /// `base` is the gateway itself, e.g. `http://pushgateway:9091`.
+    pub fn new(base: &str, timeout: Duration) -> Result<Self, reqwest::Error> {
+        let client = reqwest::ClientBuilder::new().timeout(timeout).build()?;
+
+        Ok(PushGateway {
+            client,
+            url: job_url(base),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Send the text exposition in `body`.
+    ///
+    /// This is a POST, so only metrics with the same name get replaced in the group.
+    pub async fn push(&self, body: String) -> Result<(), reqwest::Error> {
+        self.client
+            .post(&self.url)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+fn job_url(base: &str) -> String {
+    format!("{}/metrics/job/{}", base.trim_end_matches('/'), JOB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::job_url;
+
+    #[test]
+    fn job_url_ignores_trailing_slash() {
+        assert_eq!(
+            job_url("http://localhost:9091"),
+            "http://localhost:9091/metrics/job/se-stats-exporter"
+        );
+        assert_eq!(
+            job_url("http://localhost:9091/"),
+            "http://localhost:9091/metrics/job/se-stats-exporter"
+        );
+    }
+}
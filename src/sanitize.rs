@@ -0,0 +1,135 @@
+use clap::arg_enum;
+use std::{borrow::Cow, collections::HashMap};
+
+arg_enum! {
+    /// How names from the api are cleaned up before they become label values.
+    ///
+    /// - `Off` exports them as the api returns them.
+    /// - `Strip` trims whitespace and removes control characters like newlines and tabs.
+    /// - `Lowercase` strips them and lowercases what is left.
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub enum Sanitize {
+        Off,
+        Strip,
+        Lowercase,
+    }
+}
+
+// arg_enum! does not accept `#[default]` on variants
+#[allow(clippy::derivable_impls)]
+impl Default for Sanitize {
+    fn default() -> Self {
+        Sanitize::Off
+    }
+}
+
+impl Sanitize {
+    /// Clean up `value`, borrowing it if nothing had to change.
+    pub fn apply(self, value: &str) -> Cow<'_, str> {
+        if self == Sanitize::Off {
+            return Cow::Borrowed(value);
+        }
+
+        let trimmed = value.trim();
+        let mut value = if trimmed.chars().any(char::is_control) {
+            Cow::Owned(trimmed.chars().filter(|c| !c.is_control()).collect())
+        } else {
+            Cow::Borrowed(trimmed)
+        };
+
+        if self == Sanitize::Lowercase && value.chars().any(char::is_uppercase) {
+            value = Cow::Owned(value.to_lowercase());
+        }
+        value
+    }
+}
+
+/// An api entry with its sanitized name.
+///
+/// `amount` sums every entry whose name sanitized to the same value, `entry` is the first of them.
+#[derive(Debug)]
+pub struct Sanitized<'a, T> {
+    pub name: Cow<'a, str>,
+    pub amount: u64,
+    pub entry: &'a T,
+}
+
+/// Sanitize the name of every entry, merging entries that end up with the same name.
+///
+/// The entries keep their order. Also returns how many entries were merged into an earlier
+/// one, so collisions do not go unnoticed.
+pub fn sanitize_entries<'a, T, NameF, AmountF>(
+    mode: Sanitize,
+    entries: &'a [T],
+    name_f: NameF,
+    amount_f: AmountF,
+) -> (Vec<Sanitized<'a, T>>, usize)
+where
+    NameF: Fn(&'a T) -> &'a str,
+    AmountF: Fn(&T) -> u64,
+{
+    let mut sanitized: Vec<Sanitized<'a, T>> = Vec::with_capacity(entries.len());
+    if mode == Sanitize::Off {
+        sanitized.extend(entries.iter().map(|entry| Sanitized {
+            name: Cow::Borrowed(name_f(entry)),
+            amount: amount_f(entry),
+            entry,
+        }));
+        return (sanitized, 0);
+    }
+
+    let mut positions: HashMap<Cow<'a, str>, usize> = HashMap::with_capacity(entries.len());
+    let mut collisions = 0;
+    for entry in entries {
+        let name = mode.apply(name_f(entry));
+        match positions.get(&name) {
+            Some(&position) => {
+                sanitized[position].amount += amount_f(entry);
+                collisions += 1;
+            }
+            None => {
+                positions.insert(name.clone(), sanitized.len());
+                sanitized.push(Sanitized {
+                    name,
+                    amount: amount_f(entry),
+                    entry,
+                });
+            }
+        }
+    }
+
+    (sanitized, collisions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sanitize_entries, Sanitize};
+
+    #[test]
+    fn strips_newlines_and_tabs() {
+        assert_eq!(Sanitize::Strip.apply(" forsen\n"), "forsen");
+        assert_eq!(Sanitize::Strip.apply("for\tsen"), "forsen");
+        assert_eq!(Sanitize::Strip.apply("OMEGALUL"), "OMEGALUL");
+        assert_eq!(Sanitize::Lowercase.apply("\tOMEGALUL"), "omegalul");
+        assert_eq!(Sanitize::Off.apply(" forsen\n"), " forsen\n");
+    }
+
+    #[test]
+    fn collisions_are_summed_and_counted() {
+        let chatters = [("forsen", 3), ("forsen\n", 2), ("xqcow", 5), ("Forsen", 1)];
+
+        let (merged, collisions) =
+            sanitize_entries(Sanitize::Lowercase, &chatters, |c| c.0, |c| c.1);
+
+        let merged: Vec<_> = merged
+            .iter()
+            .map(|chatter| (chatter.name.as_ref(), chatter.amount))
+            .collect();
+        assert_eq!(merged, [("forsen", 6), ("xqcow", 5)]);
+        assert_eq!(collisions, 2);
+
+        let (kept, collisions) = sanitize_entries(Sanitize::Off, &chatters, |c| c.0, |c| c.1);
+        assert_eq!(kept.len(), 4);
+        assert_eq!(collisions, 0);
+    }
+}
@@ -0,0 +1,119 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+/// Like [`tokio::time::interval`], but every tick moves by up to ±`jitter`.
+///
+/// Instances started together drift apart instead of all hitting the api at once.
+/// The offsets do not add up: each tick is jittered around its place on the fixed
+/// schedule, so the average period stays `period`.
+#[derive(Debug)]
+pub struct JitteredInterval {
+    period: Duration,
+    jitter: Duration,
+    rng: StdRng,
+    /// Where the next tick would be without jitter, `None` before the first tick.
+    next: Option<Instant>,
+}
+
+impl JitteredInterval {
+    /// The jitter is capped at `period`.
+    pub fn new(period: Duration, jitter: Duration) -> Self {
+        Self::with_rng(period, jitter, StdRng::from_entropy())
+    }
+
+    /// Jitter with a fixed seed, so the offsets repeat.
+    pub fn with_seed(period: Duration, jitter: Duration, seed: u64) -> Self {
+        Self::with_rng(period, jitter, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(period: Duration, jitter: Duration, rng: StdRng) -> Self {
+        Self {
+            period,
+            jitter: jitter.min(period),
+            rng,
+            next: None,
+        }
+    }
+
+    /// A random offset in `-jitter..=jitter` as signed nanoseconds.
+    fn offset(&mut self) -> i128 {
+        let jitter = self.jitter.as_nanos() as i128;
+        if jitter == 0 {
+            return 0;
+        }
+        self.rng.gen_range(-jitter..=jitter)
+    }
+
+    fn jittered(&mut self, scheduled: Instant) -> Instant {
+        let offset = self.offset();
+        let magnitude = Duration::from_nanos(offset.unsigned_abs() as u64);
+
+        if offset >= 0 {
+            scheduled + magnitude
+        } else {
+            scheduled.checked_sub(magnitude).unwrap_or(scheduled)
+        }
+    }
+
+    /// Wait for the next tick.
+    ///
+    /// The first tick is scheduled right away, so it fires within `jitter`. A tick
+    /// that is already late fires immediately.
+    pub async fn tick(&mut self) {
+        let scheduled = self.next.unwrap_or_else(Instant::now);
+        self.next = Some(scheduled + self.period);
+
+        let deadline = self.jittered(scheduled);
+        time::sleep_until(deadline).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JitteredInterval;
+    use std::time::Duration;
+
+    const PERIOD: Duration = Duration::from_secs(10);
+
+    fn offsets(interval: &mut JitteredInterval, count: usize) -> Vec<i128> {
+        (0..count).map(|_| interval.offset()).collect()
+    }
+
+    #[test]
+    fn offsets_stay_within_the_jitter() {
+        let jitter = Duration::from_secs(2).as_nanos() as i128;
+        let mut interval = JitteredInterval::with_seed(PERIOD, Duration::from_secs(2), 7);
+
+        let offsets = offsets(&mut interval, 100);
+
+        assert!(offsets
+            .iter()
+            .all(|offset| (-jitter..=jitter).contains(offset)));
+        assert!(offsets.iter().any(|offset| *offset < 0));
+        assert!(offsets.iter().any(|offset| *offset > 0));
+    }
+
+    #[test]
+    fn seeds_repeat_and_zero_jitter_is_fixed() {
+        let seeded = |seed| {
+            let mut interval = JitteredInterval::with_seed(PERIOD, Duration::from_secs(2), seed);
+            offsets(&mut interval, 10)
+        };
+        assert_eq!(seeded(1), seeded(1));
+        assert_ne!(seeded(1), seeded(2));
+
+        let mut fixed = JitteredInterval::new(PERIOD, Duration::ZERO);
+        assert!(offsets(&mut fixed, 10).iter().all(|offset| *offset == 0));
+    }
+
+    #[tokio::test]
+    async fn the_first_tick_fires_within_the_jitter() {
+        let mut interval = JitteredInterval::with_seed(PERIOD, Duration::from_millis(50), 3);
+        let start = tokio::time::Instant::now();
+
+        interval.tick().await;
+
+        assert!(start.elapsed() <= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,416 @@
+use crate::{
+    filter::Filters,
+    labels::LabelKeys,
+    names::{is_valid_prefix, AmountKind, MetricSeparator},
+    sanitize::Sanitize,
+    state::FailurePolicy,
+    stats_api::StatsWindow,
+    ExportName,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::BTreeMap, fmt::Display, fs, io, net::SocketAddr, path::Path, str::FromStr,
+    time::Duration,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SettingsError {
+    #[error("Could not read config file {path}: {source}")]
+    ReadError { path: String, source: io::Error },
+
+    #[error("Could not parse config file {path}: {source}")]
+    ParseError {
+        path: String,
+        source: toml::de::Error,
+    },
+
+    #[error("Invalid {field}: {reason}")]
+    ValidationError { field: &'static str, reason: String },
+}
+
+/// Values read from a config file.
+///
+/// Every field is optional; flags given on the command line take precedence.
+///
+/// Serializing it as toml gives the normalized file, without the fields that are not set.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub export: Option<Vec<ExportName>>,
+    pub channels: Option<Vec<String>>,
+    pub fetch_concurrency: Option<usize>,
+    pub address: Option<SocketAddr>,
+    pub interval: Option<Interval>,
+    /// The same as `--interval-jitter`.
+    pub interval_jitter: Option<Jitter>,
+    pub request_timeout: Option<u64>,
+    /// Seconds to reuse fetched channel stats for, the same as `--cache-ttl`.
+    pub cache_ttl: Option<u64>,
+    pub limit: Option<usize>,
+    pub top_channels: Option<usize>,
+    pub emote_ids: Option<bool>,
+    pub last_message: Option<bool>,
+    /// The EMA alpha for usage amounts, the same as `--smoothing`.
+    pub smoothing: Option<f64>,
+    pub prefix: Option<String>,
+    #[serde(deserialize_with = "parse", serialize_with = "display")]
+    pub separator: Option<MetricSeparator>,
+    #[serde(deserialize_with = "parse", serialize_with = "display")]
+    pub on_failure: Option<FailurePolicy>,
+    #[serde(deserialize_with = "parse", serialize_with = "display")]
+    pub amounts: Option<AmountKind>,
+    #[serde(deserialize_with = "parse", serialize_with = "display")]
+    pub window: Option<StatsWindow>,
+    #[serde(deserialize_with = "parse", serialize_with = "display")]
+    pub sanitize: Option<Sanitize>,
+    /// Label key overrides, the same as `--label-key <metric>=<label key>`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub label_keys: BTreeMap<String, String>,
+    /// Entry names to keep per metric, the same as `--allow <metric>=<pattern>`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub allow: BTreeMap<String, Vec<String>>,
+    /// Entry names to skip per metric, the same as `--deny <metric>=<pattern>`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub deny: BTreeMap<String, Vec<String>>,
+}
+
+impl Settings {
+    pub fn from_file(path: &Path) -> Result<Self, SettingsError> {
+        let path_str = || path.display().to_string();
+
+        let content = fs::read_to_string(path).map_err(|source| SettingsError::ReadError {
+            path: path_str(),
+            source,
+        })?;
+
+        toml::from_str(&content).map_err(|source| SettingsError::ParseError {
+            path: path_str(),
+            source,
+        })
+    }
+
+    /// Check the values parsing can not, like channel names, label keys and patterns.
+    ///
+    /// Nothing is connected to or fetched.
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let invalid = |field, reason: String| Err(SettingsError::ValidationError { field, reason });
+
+        if let Some(channels) = &self.channels {
+            if channels.is_empty() {
+                return invalid("channels", String::from("at least one channel is needed"));
+            }
+            if let Some(channel) = channels.iter().find(|channel| !is_valid_channel(channel)) {
+                return invalid("channels", format!("{:?} is not a twitch channel", channel));
+            }
+        }
+        if self.fetch_concurrency == Some(0) {
+            return invalid("fetch_concurrency", String::from("has to be at least 1"));
+        }
+        if matches!(self.request_timeout, Some(seconds) if !is_valid_request_timeout(seconds)) {
+            return invalid("request_timeout", String::from("has to be at least 1"));
+        }
+        if let Some(smoothing) = self.smoothing {
+            if !is_valid_smoothing(smoothing) {
+                return invalid("smoothing", String::from("has to be above 0 and at most 1"));
+            }
+        }
+        if let Some(prefix) = &self.prefix {
+            if !is_valid_prefix(prefix) {
+                return invalid(
+                    "prefix",
+                    format!("{:?} is not a metric name prefix", prefix),
+                );
+            }
+        }
+        if let Err(e) = LabelKeys::from_overrides(&self.label_keys) {
+            return invalid("label_keys", e.to_string());
+        }
+
+        let mut filters = Filters::default();
+        for (metric, patterns) in &self.allow {
+            for pattern in patterns {
+                if let Err(e) = filters.allow(metric, pattern) {
+                    return invalid("allow", e.to_string());
+                }
+            }
+        }
+        for (metric, patterns) in &self.deny {
+            for pattern in patterns {
+                if let Err(e) = filters.deny(metric, pattern) {
+                    return invalid("deny", e.to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `channel` can be a twitch login, or is `global`.
+pub fn is_valid_channel(channel: &str) -> bool {
+    (1..=25).contains(&channel.len())
+        && channel
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Whether `seconds` can time out an api request, a zero timeout fails every request.
+pub fn is_valid_request_timeout(seconds: u64) -> bool {
+    seconds >= 1
+}
+
+/// Whether `alpha` is an EMA alpha, the weight of the newest amount.
+pub fn is_valid_smoothing(alpha: f64) -> bool {
+    alpha > 0.0 && alpha <= 1.0
+}
+
+/// Polling faster than this only hammers the api.
+pub const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The time between two exports, at least [`MIN_INTERVAL`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval(Duration);
+
+impl Interval {
+    pub fn new(interval: Duration) -> Result<Self, String> {
+        if interval < MIN_INTERVAL {
+            return Err(format!(
+                "the interval has to be at least {}s",
+                MIN_INTERVAL.as_secs()
+            ));
+        }
+
+        Ok(Self(interval))
+    }
+
+    pub fn duration(self) -> Duration {
+        self.0
+    }
+}
+
+impl FromStr for Interval {
+    type Err = String;
+
+    /// Parse a duration like `30s`, `5m` or `1h30m`; a bare number counts as seconds.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(parse_duration(s)?)
+    }
+}
+
+/// Parse a duration like `30s`, `5m` or `1h30m`; a bare number counts as seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    match s.parse() {
+        Ok(seconds) => Ok(Duration::from_secs(seconds)),
+        Err(_) => humantime::parse_duration(s).map_err(|e| {
+            format!(
+                "{:?} is not a duration ({}), expected seconds or e.g. 30s, 5m or 1h30m",
+                s, e
+            )
+        }),
+    }
+}
+
+impl Serialize for Interval {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_duration(self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Interval {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Seconds(u64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Seconds(seconds) => Self::new(Duration::from_secs(seconds)),
+            Raw::Text(text) => text.parse(),
+        }
+        .map_err(de::Error::custom)
+    }
+}
+
+/// How far each export may move away from the fixed interval, in both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Jitter(Duration);
+
+impl Jitter {
+    pub fn duration(self) -> Duration {
+        self.0
+    }
+}
+
+impl FromStr for Jitter {
+    type Err = String;
+
+    /// Parse it like an [`Interval`], but without a minimum.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_duration(s).map(Self)
+    }
+}
+
+impl Serialize for Jitter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_duration(self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Jitter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Seconds(u64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Seconds(seconds) => Ok(Self(Duration::from_secs(seconds))),
+            Raw::Text(text) => text.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+/// Parse a value with the same `FromStr` impl the command line uses.
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Write a value the way `parse` reads it back, lowercased like the possible values.
+fn display<S, T>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: Display,
+{
+    match value {
+        Some(value) => serializer.serialize_str(&value.to_string().to_lowercase()),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Interval, Settings, SettingsError};
+    use crate::{names::MetricSeparator, ExportName};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_settings() {
+        let settings: Settings = toml::from_str(
+            r#"
+            export = ["bttv", "Chatter"]
+            channels = ["global", "forsen"]
+            interval = 30
+            separator = "underscore"
+
+            [label_keys]
+            chatter = "user"
+
+            [deny]
+            chatter = ["nightbot", "/bot$/"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            settings.export,
+            Some(vec![ExportName::Bttv, ExportName::Chatter])
+        );
+        assert_eq!(
+            settings.channels,
+            Some(vec![String::from("global"), String::from("forsen")])
+        );
+        assert_eq!(
+            settings.interval.map(Interval::duration),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(settings.separator, Some(MetricSeparator::Underscore));
+        assert_eq!(settings.address, None);
+        assert_eq!(settings.label_keys["chatter"], "user");
+        assert_eq!(settings.deny["chatter"], ["nightbot", "/bot$/"]);
+        assert!(settings.allow.is_empty());
+    }
+
+    #[test]
+    fn parse_intervals() {
+        let seconds = |s: &str| s.parse::<Interval>().map(|i| i.duration().as_secs());
+
+        assert_eq!(seconds("1"), Ok(1));
+        assert_eq!(seconds("10"), Ok(10));
+        assert_eq!(seconds("30s"), Ok(30));
+        assert_eq!(seconds("5m"), Ok(300));
+        assert_eq!(seconds("1h30m"), Ok(5400));
+        assert!(seconds("500ms").is_err());
+        assert!(seconds("5 parsecs").is_err());
+        assert!(seconds("0").is_err());
+        assert!(seconds("-1").is_err());
+        assert!(seconds("").is_err());
+        assert!(toml::from_str::<Settings>("interval = 0").is_err());
+        assert_eq!(
+            toml::from_str::<Settings>(r#"interval = "2m""#)
+                .unwrap()
+                .interval
+                .map(Interval::duration),
+            Some(Duration::from_secs(120))
+        );
+    }
+
+    #[test]
+    fn validate_checks_what_parsing_can_not() {
+        let validate = |toml: &str| toml::from_str::<Settings>(toml).unwrap().validate();
+
+        assert!(validate(r#"channels = ["global", "forsen_"]"#).is_ok());
+        assert!(matches!(
+            validate(r#"channels = ["for sen"]"#),
+            Err(SettingsError::ValidationError {
+                field: "channels",
+                ..
+            })
+        ));
+        assert!(validate("channels = []").is_err());
+        assert!(validate("smoothing = 1.5").is_err());
+        assert!(validate("smoothing = 0.0").is_err());
+        assert!(validate("request_timeout = 0").is_err());
+        assert!(validate("request_timeout = 1").is_ok());
+        assert!(validate(r#"prefix = "se-stats""#).is_err());
+        assert!(validate("[label_keys]\nchatters = \"user\"").is_err());
+        assert!(validate("[deny]\nchatter = [\"/(/\"]").is_err());
+    }
+
+    #[test]
+    fn normalized_settings_parse_back() {
+        let settings: Settings = toml::from_str(
+            r#"
+            export = ["TotalMessages"]
+            interval = "1h30m"
+            separator = "Underscore"
+            window = "last7d"
+
+            [allow]
+            emote = ["/^pepe/"]
+            "#,
+        )
+        .unwrap();
+
+        let normalized = toml::to_string(&settings).unwrap();
+
+        assert!(normalized.contains(r#"separator = "underscore""#));
+        assert_eq!(toml::from_str::<Settings>(&normalized).unwrap(), settings);
+    }
+
+    #[test]
+    fn reject_unknown_values() {
+        assert!(toml::from_str::<Settings>(r#"export = ["bttv", "kappa"]"#).is_err());
+        assert!(toml::from_str::<Settings>("intervall = 30").is_err());
+    }
+}
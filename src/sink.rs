@@ -0,0 +1,110 @@
+use metrics::{GaugeValue, Key, KeyData};
+use std::{fmt::Debug, sync::Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Where [`ExportState`](crate::state::ExportState) sends the values of an export cycle.
+pub trait MetricSink: Debug + Send + Sync {
+    fn gauge(&self, key: &KeyData, value: f64);
+
+    fn counter(&self, key: &KeyData, value: u64);
+
+    /// Drop the series of `key`, it is not set again unless it comes back.
+    ///
+    /// Sinks that do not keep series ignore it.
+    fn remove(&self, _key: &KeyData) {}
+}
+
+/// Forwards to the globally installed recorder, like the `metrics` macros do.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MetricsMacroSink;
+
+impl MetricSink for MetricsMacroSink {
+    fn gauge(&self, key: &KeyData, value: f64) {
+        if let Some(recorder) = metrics::try_recorder() {
+            recorder.update_gauge(Key::Owned(key.clone()), GaugeValue::Absolute(value));
+        }
+    }
+
+    fn counter(&self, key: &KeyData, value: u64) {
+        if let Some(recorder) = metrics::try_recorder() {
+            recorder.increment_counter(Key::Owned(key.clone()), value);
+        }
+    }
+}
+
+/// Keeps every gauge, counter increment and removal in memory, in the order they were sent.
+#[derive(Debug, Default)]
+pub struct RecordingSink {
+    gauges: Mutex<Vec<(KeyData, f64)>>,
+    counters: Mutex<Vec<(KeyData, u64)>>,
+    removed: Mutex<Vec<KeyData>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn gauges(&self) -> Vec<(KeyData, f64)> {
+        self.gauges.lock().unwrap().clone()
+    }
+
+    pub fn counters(&self) -> Vec<(KeyData, u64)> {
+        self.counters.lock().unwrap().clone()
+    }
+
+    pub fn removed(&self) -> Vec<KeyData> {
+        self.removed.lock().unwrap().clone()
+    }
+}
+
+impl MetricSink for RecordingSink {
+    fn gauge(&self, key: &KeyData, value: f64) {
+        self.gauges.lock().unwrap().push((key.clone(), value));
+    }
+
+    fn counter(&self, key: &KeyData, value: u64) {
+        self.counters.lock().unwrap().push((key.clone(), value));
+    }
+
+    fn remove(&self, key: &KeyData) {
+        self.removed.lock().unwrap().push(key.clone());
+    }
+}
+
+/// One value a sink received, see [`ChannelSink`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetricSample {
+    Gauge { key: KeyData, value: f64 },
+    Counter { key: KeyData, value: u64 },
+}
+
+/// Sends every gauge and counter increment as a [`MetricSample`] to a channel.
+///
+/// Samples sent after the receiver was dropped are discarded.
+#[derive(Debug, Clone)]
+pub struct ChannelSink {
+    sender: UnboundedSender<MetricSample>,
+}
+
+impl ChannelSink {
+    pub fn new(sender: UnboundedSender<MetricSample>) -> Self {
+        Self { sender }
+    }
+}
+
+impl MetricSink for ChannelSink {
+    fn gauge(&self, key: &KeyData, value: f64) {
+        let _ = self.sender.send(MetricSample::Gauge {
+            key: key.clone(),
+            value,
+        });
+    }
+
+    fn counter(&self, key: &KeyData, value: u64) {
+        let _ = self.sender.send(MetricSample::Counter {
+            key: key.clone(),
+            value,
+        });
+    }
+}
@@ -0,0 +1,598 @@
+use crate::sink::{MetricSink, MetricsMacroSink};
+use clap::arg_enum;
+use metrics::{KeyData, Label};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+arg_enum! {
+    /// What happens to exported series when a fetch fails.
+    ///
+    /// - `Hold` keeps the last good values.
+    /// - `Zero` sets every series from earlier cycles to 0.
+    /// - `Expire` removes series from the sink once they missed two cycles.
+    #[derive(PartialEq, Debug, Clone, Copy)]
+    pub enum FailurePolicy {
+        Hold,
+        Zero,
+        Expire,
+    }
+}
+
+// arg_enum! does not accept `#[default]` on variants
+#[allow(clippy::derivable_impls)]
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        FailurePolicy::Hold
+    }
+}
+
+impl FailurePolicy {
+    /// The idle timeout the prometheus recorder needs to implement this policy.
+    ///
+    /// Only `Expire` relies on the recorder to drop removed series. With `jitter` two
+    /// ticks can be up to `interval + 2 * jitter` apart, the timeout has to outlast that.
+    pub fn idle_timeout(self, interval: Duration, jitter: Duration) -> Option<Duration> {
+        match self {
+            FailurePolicy::Expire => Some((interval + jitter) * 2),
+            FailurePolicy::Hold | FailurePolicy::Zero => None,
+        }
+    }
+}
+
+/// State carried across export cycles.
+#[derive(Debug)]
+pub struct ExportState {
+    /// Every series that holds a value, with the generation it was last emitted in.
+    ///
+    /// Updated in place, so a cycle does not have to build, compare and drop a set of
+    /// every key again.
+    series: Mutex<HashMap<KeyData, u64>>,
+    /// The generation of the current cycle, counted up by `finish_cycle`.
+    generation: AtomicU64,
+    /// The last total of every series exported as a counter, with the generation it
+    /// was last emitted in.
+    totals: Mutex<HashMap<KeyData, (u64, u64)>>,
+    /// The last total of every series exported as a delta, pruned like `series`.
+    deltas: Mutex<HashMap<KeyData, u64>>,
+    /// When the stats of each channel were last fetched successfully.
+    fetched: Mutex<HashMap<String, Instant>>,
+    /// The last smoothed value of every series exported with smoothing.
+    smoothed: Mutex<HashMap<KeyData, f64>>,
+    /// The id of the next export cycle.
+    cycles: AtomicU64,
+    sink: Arc<dyn MetricSink>,
+}
+
+impl Default for ExportState {
+    fn default() -> Self {
+        Self::with_sink(Arc::new(MetricsMacroSink))
+    }
+}
+
+impl ExportState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send every value to `sink` instead of the global recorder.
+    pub fn with_sink(sink: Arc<dyn MetricSink>) -> Self {
+        ExportState {
+            series: Mutex::default(),
+            generation: AtomicU64::new(0),
+            totals: Mutex::default(),
+            deltas: Mutex::default(),
+            fetched: Mutex::default(),
+            smoothed: Mutex::default(),
+            cycles: AtomicU64::new(0),
+            sink,
+        }
+    }
+
+    /// Set a gauge and remember its key.
+    pub(crate) fn gauge(&self, name: String, value: f64, labels: Vec<Label>) {
+        self.set_gauge(KeyData::from_parts(name, labels), value);
+    }
+
+    /// Like [`ExportState::gauge`], but sets the exponential moving average
+    /// `alpha * value + (1 - alpha) * previous` instead of `value`.
+    ///
+    /// The first value of a series is set as it is.
+    pub(crate) fn smoothed_gauge(&self, name: String, value: f64, labels: Vec<Label>, alpha: f64) {
+        let key = KeyData::from_parts(name, labels);
+        let value = {
+            let mut smoothed = self.smoothed.lock().unwrap();
+            let value = match smoothed.get(&key) {
+                Some(previous) => alpha * value + (1.0 - alpha) * previous,
+                None => value,
+            };
+            smoothed.insert(key.clone(), value);
+            value
+        };
+
+        self.set_gauge(key, value);
+    }
+
+    fn set_gauge(&self, key: KeyData, value: f64) {
+        self.sink.gauge(&key, value);
+        let generation = self.generation.load(Ordering::Relaxed);
+        self.series.lock().unwrap().insert(key, generation);
+    }
+
+    /// Set a gauge about the exporter itself, like `up`.
+    ///
+    /// It is not remembered, so [`ExportState::finish_cycle`] and the failure policies
+    /// leave it alone.
+    pub(crate) fn status_gauge(&self, name: String, value: f64, labels: Vec<Label>) {
+        self.sink.gauge(&KeyData::from_parts(name, labels), value);
+    }
+
+    /// Count `increment` more of something the exporter did, like a failed fetch.
+    pub(crate) fn count(&self, name: String, increment: u64, labels: Vec<Label>) {
+        self.sink
+            .counter(&KeyData::from_parts(name, labels), increment);
+    }
+
+    /// Turn the running `total` of a series into a counter increment.
+    ///
+    /// Counters are left alone by [`ExportState::finish_cycle`] and the failure
+    /// policies, they keep their value until the exporter restarts. Only the total of a
+    /// series that missed a cycle is forgotten, so churning top lists do not pile up.
+    /// When such a series comes back its counter picks up where it left off and grows
+    /// by the whole new total, like after the api reset its stats.
+    pub(crate) fn counter_total(&self, name: String, total: u64, labels: Vec<Label>) {
+        let key = KeyData::from_parts(name, labels);
+        let generation = self.generation.load(Ordering::Relaxed);
+        let previous = self
+            .totals
+            .lock()
+            .unwrap()
+            .insert(key.clone(), (total, generation))
+            .map(|(previous, _)| previous);
+
+        let increment = match previous {
+            // the api reset its stats, count from zero again
+            Some(previous) if total < previous => total,
+            Some(previous) => total - previous,
+            None => total,
+        };
+        self.sink.counter(&key, increment);
+    }
+
+    /// Hand out the id of a new export cycle, counting up from 0.
+    pub(crate) fn next_cycle(&self) -> u64 {
+        self.cycles.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Set a gauge to how much the running `total` of a series grew since the last call.
+    ///
+    /// A shrinking total means the api reset its stats, that sets 0 like the first call.
+    /// So does a series that comes back after missing a cycle, its growth in the
+    /// meantime does not belong to one interval.
+    pub(crate) fn delta_gauge(&self, name: String, total: u64, labels: Vec<Label>) {
+        let key = KeyData::from_parts(name, labels);
+        let previous = self.deltas.lock().unwrap().insert(key.clone(), total);
+
+        let delta = previous.map_or(0, |previous| total.saturating_sub(previous));
+        self.set_gauge(key, delta as f64);
+    }
+
+    /// Remember that the stats of `channel` were just fetched.
+    pub(crate) fn record_fetch(&self, channel: &str) {
+        self.fetched
+            .lock()
+            .unwrap()
+            .insert(channel.to_string(), Instant::now());
+    }
+
+    /// How long ago the stats of every channel fetched so far were fetched.
+    pub fn data_ages(&self) -> Vec<(String, Duration)> {
+        self.fetched
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(channel, fetched_at)| (channel.clone(), fetched_at.elapsed()))
+            .collect()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn emitted(&self) -> Vec<KeyData> {
+        let generation = self.generation.load(Ordering::Relaxed);
+        self.series
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, emitted)| **emitted == generation)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Apply `policy` to every series from earlier cycles for which `affected` returns true.
+    pub fn on_failure<F>(&self, policy: FailurePolicy, affected: F)
+    where
+        F: Fn(&KeyData) -> bool,
+    {
+        let generation = self.generation.load(Ordering::Relaxed);
+        let earlier = |key: &KeyData, emitted: u64| emitted != generation && affected(key);
+
+        // counters are not reset, a failed fetch does not mean the series dropped out
+        self.totals
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter(|(key, (_, emitted))| earlier(key, *emitted))
+            .for_each(|(_, (_, emitted))| *emitted = generation);
+
+        let stale = {
+            let mut series = self.series.lock().unwrap();
+            if policy == FailurePolicy::Hold {
+                // carry them over so finish_cycle does not reset them
+                series
+                    .iter_mut()
+                    .filter(|(key, emitted)| earlier(key, **emitted))
+                    .for_each(|(_, emitted)| *emitted = generation);
+                return;
+            }
+            take_series(&mut series, earlier)
+        };
+
+        // the lock is released first, a sink that panics must not poison it
+        for key in stale.iter() {
+            match policy {
+                FailurePolicy::Hold => {}
+                FailurePolicy::Zero => self.sink.gauge(key, 0.0),
+                FailurePolicy::Expire => self.sink.remove(key),
+            }
+        }
+    }
+
+    /// Set every series that was live but not emitted in this cycle to 0 and remove it.
+    ///
+    /// Entries that drop out of a top list would otherwise keep their last value forever.
+    /// Sinks that expire series show the 0 until they drop it.
+    pub fn finish_cycle(&self) {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+
+        let dropped = {
+            let mut series = self.series.lock().unwrap();
+            let dropped = take_series(&mut series, |_, emitted| emitted != generation);
+            // a series that comes back starts over instead of rising from 0
+            self.smoothed
+                .lock()
+                .unwrap()
+                .retain(|key, _| series.contains_key(key));
+            self.deltas
+                .lock()
+                .unwrap()
+                .retain(|key, _| series.contains_key(key));
+            dropped
+        };
+        self.totals
+            .lock()
+            .unwrap()
+            .retain(|_, (_, emitted)| *emitted == generation);
+
+        for key in dropped.iter() {
+            self.sink.gauge(key, 0.0);
+            self.sink.remove(key);
+        }
+    }
+}
+
+/// Remove every series for which `stale` returns true and return their keys.
+fn take_series<F>(series: &mut HashMap<KeyData, u64>, stale: F) -> Vec<KeyData>
+where
+    F: Fn(&KeyData, u64) -> bool,
+{
+    let keys: Vec<KeyData> = series
+        .iter()
+        .filter(|(key, emitted)| stale(key, **emitted))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in keys.iter() {
+        series.remove(key);
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExportState, FailurePolicy};
+    use crate::{
+        exposition::ExpiringRecorder,
+        sink::{MetricSink, RecordingSink},
+    };
+    use metrics::{KeyData, Label};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use metrics_util::MetricKindMask;
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    fn recorded(idle_timeout: Option<Duration>) -> (ExpiringRecorder, ExportState) {
+        let recorder = PrometheusBuilder::new()
+            .idle_timeout(MetricKindMask::GAUGE, idle_timeout)
+            .build();
+        let recorder = ExpiringRecorder::new(recorder, idle_timeout);
+        (recorder.clone(), ExportState::with_sink(Arc::new(recorder)))
+    }
+
+    fn emit(state: &ExportState, emote: &'static str) {
+        state.gauge(
+            String::from("sestats.emote"),
+            42.0,
+            vec![Label::new("emote", emote)],
+        );
+    }
+
+    fn emit_cycle(state: &ExportState) {
+        emit(state, "Kappa");
+        state.finish_cycle();
+    }
+
+    #[test]
+    fn hold_keeps_values() {
+        let (sink, state) = recorded(None);
+
+        emit_cycle(&state);
+        state.on_failure(FailurePolicy::Hold, |_| true);
+        state.finish_cycle();
+
+        assert!(sink.render().contains("sestats_emote{emote=\"Kappa\"} 42"));
+    }
+
+    #[test]
+    fn zero_resets_values() {
+        let (sink, state) = recorded(None);
+
+        emit_cycle(&state);
+        state.on_failure(FailurePolicy::Zero, |_| true);
+
+        assert!(sink.render().contains("sestats_emote{emote=\"Kappa\"} 0"));
+    }
+
+    #[test]
+    fn expire_removes_series() {
+        let policy = FailurePolicy::Expire;
+        let (sink, state) = recorded(policy.idle_timeout(Duration::from_millis(1), Duration::ZERO));
+
+        emit_cycle(&state);
+        state.status_gauge(String::from("sestats.up"), 1.0, vec![]);
+        // the recorder only notices idle series while rendering
+        assert!(sink.render().contains("sestats_emote"));
+
+        state.on_failure(policy, |_| true);
+        thread::sleep(Duration::from_millis(50));
+        sink.refresh();
+
+        let output = sink.render();
+        assert!(!output.contains("sestats_emote"));
+        // only the series the state tracks expire
+        assert!(output.contains("sestats_up 1"));
+    }
+
+    #[test]
+    fn expire_timeout_outlasts_jittered_ticks() {
+        let interval = Duration::from_secs(10);
+
+        for &jitter in [Duration::ZERO, Duration::from_secs(3), interval].iter() {
+            let timeout = FailurePolicy::Expire
+                .idle_timeout(interval, jitter)
+                .unwrap();
+            assert!(timeout > interval + jitter * 2, "{:?}", jitter);
+        }
+        assert_eq!(FailurePolicy::Hold.idle_timeout(interval, interval), None);
+        assert_eq!(FailurePolicy::Zero.idle_timeout(interval, interval), None);
+    }
+
+    #[test]
+    fn expired_and_dropped_series_are_removed_from_the_sink() {
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+        let key = |emote| KeyData::from_parts("sestats.emote", vec![Label::new("emote", emote)]);
+
+        emit(&state, "Kappa");
+        emit(&state, "LUL");
+        state.finish_cycle();
+        emit(&state, "Kappa");
+        state.finish_cycle();
+        state.on_failure(FailurePolicy::Expire, |_| true);
+
+        assert_eq!(sink.removed(), [key("LUL"), key("Kappa")]);
+        assert!(state.emitted().is_empty());
+    }
+
+    /// Takes values, but panics when a series is removed.
+    #[derive(Debug)]
+    struct PanicOnRemoveSink;
+
+    impl MetricSink for PanicOnRemoveSink {
+        fn gauge(&self, _key: &KeyData, _value: f64) {}
+
+        fn counter(&self, _key: &KeyData, _value: u64) {}
+
+        fn remove(&self, _key: &KeyData) {
+            panic!("sink broke");
+        }
+    }
+
+    #[test]
+    fn a_panicking_sink_does_not_poison_the_state() {
+        let state = ExportState::with_sink(Arc::new(PanicOnRemoveSink));
+
+        emit_cycle(&state);
+        let finished = panic::catch_unwind(AssertUnwindSafe(|| state.finish_cycle()));
+        assert!(finished.is_err());
+
+        emit(&state, "Kappa");
+        let failed = panic::catch_unwind(AssertUnwindSafe(|| {
+            state.on_failure(FailurePolicy::Expire, |_| true)
+        }));
+        assert!(failed.is_ok());
+
+        // the cycle after the panics works as usual
+        emit_cycle(&state);
+        emit(&state, "LUL");
+        assert_eq!(state.emitted().len(), 1);
+    }
+
+    #[test]
+    fn status_gauges_are_not_reset() {
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+
+        state.status_gauge(String::from("sestats.up"), 1.0, vec![]);
+        state.count(String::from("sestats.fetch_errors_total"), 1, vec![]);
+        state.finish_cycle();
+        state.on_failure(FailurePolicy::Zero, |_| true);
+
+        assert_eq!(sink.gauges(), [(KeyData::from_name("sestats.up"), 1.0)]);
+        assert_eq!(
+            sink.counters(),
+            [(KeyData::from_name("sestats.fetch_errors_total"), 1)]
+        );
+        assert!(state.emitted().is_empty());
+    }
+
+    #[test]
+    fn counter_totals_become_increments() {
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+
+        for &total in [10, 15, 15, 4].iter() {
+            state.counter_total(String::from("sestats.emote"), total, vec![]);
+        }
+
+        let increments: Vec<u64> = sink
+            .counters()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(increments, vec![10, 5, 0, 4]);
+        assert!(state.emitted().is_empty());
+    }
+
+    #[test]
+    fn counter_totals_are_forgotten_once_they_drop_out() {
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+        let count = |total| state.counter_total(String::from("sestats.emote"), total, vec![]);
+
+        count(10);
+        state.finish_cycle();
+        // a failure does not forget the total
+        state.on_failure(FailurePolicy::Zero, |_| true);
+        state.finish_cycle();
+        count(12);
+        state.finish_cycle();
+        // missing from this cycle
+        state.finish_cycle();
+        count(20);
+
+        let increments: Vec<u64> = sink
+            .counters()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(increments, vec![10, 2, 20]);
+    }
+
+    #[test]
+    fn smoothing_averages_with_the_previous_value() {
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+        let labels = || vec![Label::new("emote", "Kappa")];
+
+        state.smoothed_gauge(String::from("sestats.emote"), 10.0, labels(), 0.5);
+        state.smoothed_gauge(String::from("sestats.emote"), 20.0, labels(), 0.5);
+        state.smoothed_gauge(String::from("sestats.emote"), 20.0, labels(), 0.5);
+
+        let values: Vec<f64> = sink.gauges().into_iter().map(|(_, value)| value).collect();
+        assert_eq!(values, [10.0, 15.0, 17.5]);
+    }
+
+    #[test]
+    fn deltas_are_clamped_at_zero() {
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+        let labels = || vec![Label::new("emote", "Kappa")];
+
+        for &total in [10, 25, 25, 5, 8].iter() {
+            state.delta_gauge(String::from("sestats.emote_delta"), total, labels());
+        }
+
+        let values: Vec<f64> = sink.gauges().into_iter().map(|(_, value)| value).collect();
+        assert_eq!(values, [0.0, 15.0, 0.0, 0.0, 3.0]);
+    }
+
+    #[test]
+    fn returning_deltas_start_at_zero() {
+        let sink = Arc::new(RecordingSink::new());
+        let state = ExportState::with_sink(sink.clone());
+        let delta = |total| {
+            state.delta_gauge(
+                String::from("sestats.emote_delta"),
+                total,
+                vec![Label::new("emote", "Kappa")],
+            )
+        };
+
+        delta(10);
+        state.finish_cycle();
+        delta(15);
+        state.finish_cycle();
+        // missing from this cycle
+        state.finish_cycle();
+        delta(100);
+        state.finish_cycle();
+
+        let values: Vec<f64> = sink.gauges().into_iter().map(|(_, value)| value).collect();
+        // the 0.0 before the last value is the one finish_cycle sets for the missed cycle
+        assert_eq!(values, [0.0, 5.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn cycles_count_up() {
+        let state = ExportState::new();
+
+        assert_eq!(state.next_cycle(), 0);
+        assert_eq!(state.next_cycle(), 1);
+    }
+
+    #[test]
+    fn data_ages_grow_until_the_next_fetch() {
+        let state = ExportState::new();
+        assert!(state.data_ages().is_empty());
+
+        state.record_fetch("forsen");
+        thread::sleep(Duration::from_millis(20));
+        let (channel, age) = state.data_ages().pop().unwrap();
+        assert_eq!(channel, "forsen");
+        assert!(age >= Duration::from_millis(20));
+
+        state.record_fetch("forsen");
+        assert!(state.data_ages()[0].1 < age);
+    }
+
+    #[test]
+    fn dropped_series_go_to_zero() {
+        let (sink, state) = recorded(None);
+
+        emit(&state, "Kappa");
+        emit(&state, "LUL");
+        state.finish_cycle();
+        emit_cycle(&state);
+
+        let output = sink.render();
+        assert!(output.contains("sestats_emote{emote=\"Kappa\"} 42"));
+        assert!(output.contains("sestats_emote{emote=\"LUL\"} 0"));
+    }
+}
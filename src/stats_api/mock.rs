@@ -0,0 +1,161 @@
+//! A local stand-in for the StreamElements api.
+
+use flate2::{write::GzEncoder, Compression};
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_ENCODING, RETRY_AFTER, USER_AGENT},
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use reqwest::Url;
+use std::{
+    convert::Infallible,
+    io::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+const GLOBAL_STATS: &str = include_str!("../../tests/fixtures/global_stats.json");
+const TOP_CHANNELS: &str = include_str!("../../tests/fixtures/top_channels.json");
+const CHAT_EMOTES: &str = include_str!("../../tests/fixtures/chat_emotes.json");
+
+#[derive(Debug, Clone)]
+struct RecordedRequest {
+    path: String,
+    query: Option<String>,
+    user_agent: Option<String>,
+    authorization: Option<String>,
+}
+
+/// Answers the chatstats endpoints with the fixtures and records every request.
+pub(crate) struct MockApi {
+    url: Url,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl MockApi {
+    /// Start serving on a random local port. Needs a running tokio runtime.
+    pub(crate) fn start() -> Self {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    recorded.lock().unwrap().push(RecordedRequest {
+                        path: request.uri().path().to_string(),
+                        query: request.uri().query().map(String::from),
+                        user_agent: request
+                            .headers()
+                            .get(USER_AGENT)
+                            .and_then(|value| value.to_str().ok())
+                            .map(String::from),
+                        authorization: request
+                            .headers()
+                            .get(AUTHORIZATION)
+                            .and_then(|value| value.to_str().ok())
+                            .map(String::from),
+                    });
+                    let response = respond(request.uri().path());
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        MockApi { url, requests }
+    }
+
+    pub(crate) fn url(&self) -> Url {
+        self.url.clone()
+    }
+
+    /// The paths of all requests so far.
+    pub(crate) fn requests(&self) -> Vec<String> {
+        self.recorded()
+            .into_iter()
+            .map(|request| request.path)
+            .collect()
+    }
+
+    /// The query strings of all requests so far.
+    pub(crate) fn queries(&self) -> Vec<Option<String>> {
+        self.recorded()
+            .into_iter()
+            .map(|request| request.query)
+            .collect()
+    }
+
+    /// The user agents of all requests so far.
+    pub(crate) fn user_agents(&self) -> Vec<Option<String>> {
+        self.recorded()
+            .into_iter()
+            .map(|request| request.user_agent)
+            .collect()
+    }
+
+    /// The authorization headers of all requests so far.
+    pub(crate) fn authorizations(&self) -> Vec<Option<String>> {
+        self.recorded()
+            .into_iter()
+            .map(|request| request.authorization)
+            .collect()
+    }
+
+    fn recorded(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn respond(path: &str) -> Response<Body> {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    let (status, body) = match segments.as_slice() {
+        // a base url for an api that is down altogether
+        ["down", ..] => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "<html><body>503 Service Unavailable</body></html>",
+        ),
+        ["chatstats"] => (StatusCode::OK, TOP_CHANNELS),
+        // channel names that make the mock misbehave
+        ["chatstats", "ratelimited", "stats"] => return ratelimited(Some("1")),
+        ["chatstats", "ratelimited_long", "stats"] => return ratelimited(Some("3600")),
+        ["chatstats", "ratelimited_silent", "stats"] => return ratelimited(None),
+        ["chatstats", "unavailable", _] => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "<html><body>503 Service Unavailable</body></html>",
+        ),
+        ["chatstats", "gzipped", "stats"] => return gzipped(GLOBAL_STATS),
+        ["chatstats", _, "stats"] => (StatusCode::OK, GLOBAL_STATS),
+        ["chatstats", _, "emotes"] => (StatusCode::OK, CHAT_EMOTES),
+        _ => (StatusCode::NOT_FOUND, ""),
+    };
+
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn ratelimited(retry_after: Option<&str>) -> Response<Body> {
+    let mut builder = Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("x-ratelimit-remaining", "0");
+    if let Some(retry_after) = retry_after {
+        builder = builder.header(RETRY_AFTER, retry_after);
+    }
+    builder.body(Body::empty()).unwrap()
+}
+
+fn gzipped(body: &str) -> Response<Body> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body.as_bytes()).unwrap();
+
+    Response::builder()
+        .header(CONTENT_ENCODING, "gzip")
+        .body(Body::from(encoder.finish().unwrap()))
+        .unwrap()
+}
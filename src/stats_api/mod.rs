@@ -1,6 +1,24 @@
+use crate::names::{Metric, MetricSeparator, DEFAULT_PREFIX};
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
-use std::{borrow::Cow, collections::HashMap};
+use clap::arg_enum;
+use metrics::{counter, gauge};
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER, USER_AGENT},
+    Proxy, Response, StatusCode, Url,
+};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time;
+use tracing::debug;
+
+#[cfg(test)]
+pub(crate) mod mock;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -14,37 +32,137 @@ pub enum Error {
         source: reqwest::Error,
     },
 
+    #[error("Timed out sending {method} request to {url}")]
+    RequestTimeoutError {
+        method: &'static str,
+        url: String,
+        source: reqwest::Error,
+    },
+
     #[error("Could not parse json")]
     ParseJsonError(#[source] reqwest::Error),
+
+    #[error("{url} answered with {status}")]
+    HttpStatus { status: StatusCode, url: String },
+
+    #[error("Rate limited by {url}")]
+    RateLimited {
+        url: String,
+        /// How long the api asked us to wait, if it said so.
+        retry_after: Option<Duration>,
+    },
+}
+
+impl Error {
+    /// A short description of what went wrong, used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::BuildClientError(_) => "client",
+            Error::SendRequestError { .. } => "network",
+            Error::RequestTimeoutError { .. } => "timeout",
+            Error::ParseJsonError(_) => "parse",
+            Error::HttpStatus { .. } => "http",
+            Error::RateLimited { .. } => "rate_limited",
+        }
+    }
+}
+
+/// Used by [`ApiClient::new`].
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Used unless [`ApiClient::with_base_url`] says otherwise.
+pub const DEFAULT_BASE_URL: &str = "https://api.streamelements.com/kappa/v2/";
+
+/// Sent with every request unless [`ApiClient::with_user_agent`] says otherwise.
+pub const DEFAULT_USER_AGENT: &str = concat!("se-stats-exporter/", env!("CARGO_PKG_VERSION"));
+
+/// How often a failed request gets retried by default.
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// The delay before the first retry, doubled on every further attempt.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// The longest a rate limited request waits before it is retried by default.
+pub const DEFAULT_MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+arg_enum! {
+    /// The time span channel stats cover.
+    #[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+    pub enum StatsWindow {
+        AllTime,
+        Last7d,
+        Last30d,
+    }
+}
+
+// arg_enum! does not accept `#[default]` on variants
+#[allow(clippy::derivable_impls)]
+impl Default for StatsWindow {
+    fn default() -> Self {
+        StatsWindow::AllTime
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl StatsWindow {
+    /// The value of the `interval` query parameter, all-time stats need none.
+    fn interval(self) -> Option<&'static str> {
+        match self {
+            StatsWindow::AllTime => None,
+            StatsWindow::Last7d => Some("7d"),
+            StatsWindow::Last30d => Some("30d"),
+        }
+    }
+
+    /// The value of the `window` label.
+    pub fn label(self) -> &'static str {
+        self.interval().unwrap_or("alltime")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Channel<'a> {
     pub channel: Cow<'a, str>,
     pub messages: u64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatEmotes<'a> {
     pub username: Cow<'a, str>,
     pub emotes: EmoteList<'a>,
-    #[serde(rename = "lastMessage")]
-    pub last_message: DateTime<Utc>,
+    /// `None` when the user never wrote in chat.
+    #[serde(default, rename = "lastMessage")]
+    pub last_message: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmoteList<'a> {
-    #[serde(rename = "bttvGlobalEmotes")]
+    #[serde(
+        default,
+        deserialize_with = "null_as_default",
+        rename = "bttvGlobalEmotes"
+    )]
     pub bttv_global_emotes: HashMap<Cow<'a, str>, Emote<'a>>,
-    #[serde(rename = "bttvChannelEmotes")]
+    #[serde(
+        default,
+        deserialize_with = "null_as_default",
+        rename = "bttvChannelEmotes"
+    )]
     pub bttv_channel_emotes: HashMap<Cow<'a, str>, Emote<'a>>,
-    #[serde(rename = "ffzGlobalEmotes")]
+    #[serde(
+        default,
+        deserialize_with = "null_as_default",
+        rename = "ffzGlobalEmotes"
+    )]
     pub ffz_global_emotes: HashMap<Cow<'a, str>, Emote<'a>>,
-    #[serde(rename = "ffzChannelEmotes")]
+    #[serde(
+        default,
+        deserialize_with = "null_as_default",
+        rename = "ffzChannelEmotes"
+    )]
     pub ffz_channel_emotes: HashMap<Cow<'a, str>, Emote<'a>>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Emote<'a> {
     pub name: Cow<'a, str>,
     #[serde(rename = "_id")]
@@ -56,147 +174,918 @@ pub struct Emote<'a> {
     pub gif: bool,
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
-#[serde(rename = "lowercase")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum EmoteType {
     BTTV,
     FFZ,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatStats<'a> {
     pub channel: Cow<'a, str>,
     #[serde(rename = "totalMessages")]
     pub total_messages: u64,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub chatters: Cow<'a, [ChatterStats<'a>]>,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub hashtags: Cow<'a, [HashtagStats<'a>]>,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub commands: Cow<'a, [CommandStats<'a>]>,
-    #[serde(rename = "bttvEmotes")]
+    #[serde(default, deserialize_with = "null_as_default", rename = "bttvEmotes")]
     pub bttv_emotes: Cow<'a, [EmoteStats<'a>]>,
-    #[serde(rename = "ffzEmotes")]
+    #[serde(default, deserialize_with = "null_as_default", rename = "ffzEmotes")]
     pub ffz_emotes: Cow<'a, [EmoteStats<'a>]>,
-    #[serde(rename = "twitchEmotes")]
+    #[serde(default, deserialize_with = "null_as_default", rename = "twitchEmotes")]
     pub twitch_emotes: Cow<'a, [EmoteStats<'a>]>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Small channels may send `null` or leave lists out, both mean empty.
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Option::unwrap_or_default)
+}
+
+/// The chatstats endpoints never return more than 100 entries per list.
+pub const MAX_ENTRIES: usize = 100;
+
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+pub enum ValidationError {
+    #[error("{field} has {len} entries but the api returns at most {max}")]
+    TooManyEntries {
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+
+    #[error("Summed emote usage overflows u64")]
+    EmoteUsageOverflow,
+
+    #[error(
+        "Total messages ({total_messages}) is smaller than summed emote usage ({emote_usage})"
+    )]
+    TotalBelowEmoteUsage {
+        total_messages: u64,
+        emote_usage: u64,
+    },
+}
+
+impl<'a> ChatStats<'a> {
+    /// Run a few cheap heuristics to detect obviously corrupt payloads.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let lengths = [
+            ("chatters", self.chatters.len()),
+            ("hashtags", self.hashtags.len()),
+            ("commands", self.commands.len()),
+            ("bttvEmotes", self.bttv_emotes.len()),
+            ("ffzEmotes", self.ffz_emotes.len()),
+            ("twitchEmotes", self.twitch_emotes.len()),
+        ];
+
+        for &(field, len) in lengths.iter() {
+            if len > MAX_ENTRIES {
+                return Err(ValidationError::TooManyEntries {
+                    field,
+                    len,
+                    max: MAX_ENTRIES,
+                });
+            }
+        }
+
+        let emote_usage = self
+            .bttv_emotes
+            .iter()
+            .chain(self.ffz_emotes.iter())
+            .chain(self.twitch_emotes.iter())
+            .try_fold(0u64, |sum, emote| sum.checked_add(emote.amount))
+            .ok_or(ValidationError::EmoteUsageOverflow)?;
+
+        if self.total_messages < emote_usage {
+            return Err(ValidationError::TotalBelowEmoteUsage {
+                total_messages: self.total_messages,
+                emote_usage,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatterStats<'a> {
     pub name: Cow<'a, str>,
     pub amount: u64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HashtagStats<'a> {
     pub hashtag: Cow<'a, str>,
     pub amount: u64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandStats<'a> {
     pub command: Cow<'a, str>,
     pub amount: u64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmoteStats<'a> {
     pub id: Cow<'a, str>,
     pub emote: Cow<'a, str>,
     pub amount: u64,
 }
 
+/// Stats by channel and window with the time they were fetched at.
+type StatsCache = HashMap<(String, StatsWindow), (Instant, ChatStats<'static>)>;
+
+/// A bearer token, kept out of debug output so it does not end up in logs.
+#[derive(Clone)]
+struct Token(String);
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(..)")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ApiClient {
     client: reqwest::Client,
+    timeout: Duration,
+    base_url: Url,
+    user_agent: String,
+    token: Option<Token>,
+    prefix: String,
+    separator: MetricSeparator,
+    max_retries: u32,
+    retry_delay: Duration,
+    max_retry_delay: Duration,
+    cache_ttl: Option<Duration>,
+    /// Shared between clones.
+    cache: Arc<Mutex<StatsCache>>,
 }
 
 impl ApiClient {
     pub fn new() -> Result<ApiClient, Error> {
-        let client = reqwest::ClientBuilder::new()
+        Self::with_timeout(DEFAULT_TIMEOUT)
+    }
+
+    /// Create a client whose requests fail once `timeout` has elapsed.
+    ///
+    /// The proxies in `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` are honored until
+    /// [`ApiClient::with_proxy`] replaces them.
+    pub fn with_timeout(timeout: Duration) -> Result<ApiClient, Error> {
+        let client = client_builder(timeout)
+            .build()
+            .map_err(Error::BuildClientError)?;
+
+        Ok(ApiClient {
+            client,
+            timeout,
+            base_url: Url::parse(DEFAULT_BASE_URL).unwrap(),
+            user_agent: String::from(DEFAULT_USER_AGENT),
+            token: None,
+            prefix: String::from(DEFAULT_PREFIX),
+            separator: MetricSeparator::default(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            max_retry_delay: DEFAULT_MAX_RETRY_DELAY,
+            cache_ttl: None,
+            cache: Arc::default(),
+        })
+    }
+
+    /// Send every request through `proxy` instead of the proxies from the environment.
+    pub fn with_proxy(mut self, proxy: Proxy) -> Result<Self, Error> {
+        self.client = client_builder(self.timeout)
+            .proxy(proxy)
             .build()
-            .map_err(|e| Error::BuildClientError(e))?;
+            .map_err(Error::BuildClientError)?;
+
+        Ok(self)
+    }
+
+    /// Retry network errors and 5xx responses up to `max_retries` times.
+    ///
+    /// The first retry waits `base_delay`, every further one twice as long as the last.
+    pub fn with_retries(mut self, max_retries: u32, base_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_delay = base_delay;
+        self
+    }
+
+    /// Wait at most `max_delay` before retrying a rate limited request.
+    ///
+    /// A `Retry-After` above it returns [`Error::RateLimited`] right away, e.g. set it to
+    /// the export interval so a cycle never waits past the next one.
+    pub fn with_max_retry_delay(mut self, max_delay: Duration) -> Self {
+        self.max_retry_delay = max_delay;
+        self
+    }
+
+    /// Send requests to `base_url` instead of the StreamElements api, e.g. a mock
+    /// server or a caching proxy.
+    pub fn with_base_url(mut self, mut base_url: Url) -> Self {
+        // without it the last segment would be replaced instead of extended
+        if !base_url.path().ends_with('/') {
+            let path = format!("{}/", base_url.path());
+            base_url.set_path(&path);
+        }
+
+        self.base_url = base_url;
+        self
+    }
+
+    /// Answer [`ApiClient::get_stats`] from memory for `ttl` after each fetch.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Identify as `user_agent` instead of [`DEFAULT_USER_AGENT`].
+    pub fn with_user_agent(mut self, user_agent: String) -> Self {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Send `Authorization: Bearer <token>` with every request.
+    ///
+    /// The public chatstats endpoints work without one.
+    pub fn with_token(mut self, token: String) -> Self {
+        self.token = Some(Token(token));
+        self
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Set the prefix used for the request metrics.
+    pub fn with_prefix(mut self, prefix: String) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Set the separator used for the request metrics.
+    pub fn with_separator(mut self, separator: MetricSeparator) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    async fn get<T>(&self, endpoint: &'static str, url: &str) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let mut attempt = 0;
+
+        let response = loop {
+            let backoff = self.retry_delay * 2u32.saturating_pow(attempt);
 
-        Ok(ApiClient { client })
+            let delay = match self.send(endpoint, url).await {
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    let retry_after = retry_after(response.headers());
+                    let too_long =
+                        matches!(retry_after, Some(delay) if delay > self.max_retry_delay);
+                    if attempt >= self.max_retries || too_long {
+                        return Err(Error::RateLimited {
+                            url: url.to_string(),
+                            retry_after,
+                        });
+                    }
+
+                    debug!(
+                        attempt = attempt + 1,
+                        ?retry_after,
+                        "Rate limited, retrying {}",
+                        url
+                    );
+                    retry_after.unwrap_or(backoff).min(self.max_retry_delay)
+                }
+                Ok(response)
+                    if !response.status().is_server_error() || attempt >= self.max_retries =>
+                {
+                    break response
+                }
+                Err(e) if attempt >= self.max_retries => return Err(e),
+                Ok(response) => {
+                    debug!(
+                        attempt = attempt + 1,
+                        status = %response.status(),
+                        "Retrying {}",
+                        url
+                    );
+                    backoff
+                }
+                Err(e) => {
+                    debug!(attempt = attempt + 1, "Retrying {}: {}", url, e);
+                    backoff
+                }
+            };
+
+            time::sleep(delay).await;
+            attempt += 1;
+        };
+
+        // error pages would otherwise show up as parse errors
+        if !response.status().is_success() {
+            return Err(Error::HttpStatus {
+                status: response.status(),
+                url: url.to_string(),
+            });
+        }
+
+        response.json().await.map_err(|e| {
+            if e.is_timeout() {
+                Error::RequestTimeoutError {
+                    method: "GET",
+                    url: url.to_string(),
+                    source: e,
+                }
+            } else {
+                Error::ParseJsonError(e)
+            }
+        })
+    }
+
+    async fn send(&self, endpoint: &'static str, url: &str) -> Result<Response, Error> {
+        let mut request = self.client.get(url).header(USER_AGENT, &self.user_agent);
+        if let Some(Token(token)) = &self.token {
+            // marked sensitive, so reqwest does not log it either
+            request = request.bearer_auth(token);
+        }
+
+        match request.send().await {
+            Err(source) => {
+                self.count_request(endpoint, "error");
+
+                if source.is_timeout() {
+                    Err(Error::RequestTimeoutError {
+                        method: "GET",
+                        url: url.to_string(),
+                        source,
+                    })
+                } else {
+                    Err(Error::SendRequestError {
+                        method: "GET",
+                        url: url.to_string(),
+                        source,
+                    })
+                }
+            }
+            Ok(response) => {
+                self.count_request(endpoint, status_class(response.status()));
+                if let Some(remaining) = ratelimit_remaining(response.headers()) {
+                    gauge!(
+                        Metric::RateLimitRemaining.name(&self.prefix, self.separator),
+                        remaining
+                    );
+                }
+                Ok(response)
+            }
+        }
+    }
+
+    fn count_request(&self, endpoint: &'static str, status: &'static str) {
+        counter!(
+            Metric::ApiRequests.name(&self.prefix, self.separator),
+            1,
+            "endpoint" => endpoint,
+            "status" => status
+        );
     }
 
     pub async fn get_top_channels<'a>(&self) -> Result<Cow<'a, [Channel<'a>]>, Error> {
-        const URL: &str = "https://api.streamelements.com/kappa/v2/chatstats";
+        self.get("top_channels", &self.url("chatstats")).await
+    }
 
-        let channels = self
-            .client
-            .get(URL)
-            .send()
-            .await
-            .map_err(|source| Error::SendRequestError {
-                method: "GET",
-                url: URL.to_string(),
-                source,
-            })?
-            .json()
-            .await
-            .map_err(|e| Error::ParseJsonError(e))?;
+    /// Check that the api is reachable by fetching a single top channel.
+    pub async fn healthcheck(&self) -> Result<(), Error> {
+        self.get::<Vec<Channel>>("healthcheck", &self.url("chatstats?limit=1"))
+            .await?;
 
-        Ok(channels)
+        Ok(())
     }
 
-    pub async fn get_stats<'a, S>(&self, channel: S) -> Result<ChatStats<'a>, Error>
+    pub async fn get_chat_emotes<'a, S>(&self, channel: S) -> Result<ChatEmotes<'a>, Error>
     where
         S: AsRef<str>,
     {
-        let url = format!(
-            "https://api.streamelements.com/kappa/v2/chatstats/{}/stats",
-            channel.as_ref()
-        );
+        let url = self.url(&format!("chatstats/{}/emotes", channel.as_ref()));
 
-        let stats = self
-            .client
-            .get(&url)
-            .send()
-            .await
-            .map_err(|source| Error::SendRequestError {
-                method: "GET",
-                url: url.clone(),
-                source,
-            })?
-            .json()
-            .await
-            .map_err(|e| Error::ParseJsonError(e))?;
+        self.get("chat_emotes", &url).await
+    }
+
+    pub async fn get_stats<S>(&self, channel: S) -> Result<ChatStats<'static>, Error>
+    where
+        S: AsRef<str>,
+    {
+        self.get_windowed_stats(channel, StatsWindow::AllTime).await
+    }
+
+    /// Like [`ApiClient::get_stats`], but only counts what happened within `window`.
+    ///
+    /// Windows the api does not know end up as [`Error::HttpStatus`] or
+    /// [`Error::ParseJsonError`], like any other bad response.
+    pub async fn get_windowed_stats<S>(
+        &self,
+        channel: S,
+        window: StatsWindow,
+    ) -> Result<ChatStats<'static>, Error>
+    where
+        S: AsRef<str>,
+    {
+        let channel = channel.as_ref();
+        let ttl = match self.cache_ttl {
+            Some(ttl) => ttl,
+            None => return self.fetch_stats(channel, window).await,
+        };
+
+        let key = (channel.to_string(), window);
+        if let Some((fetched_at, stats)) = self.cache.lock().unwrap().get(&key) {
+            if fetched_at.elapsed() < ttl {
+                debug!("Using cached stats for {}", channel);
+                return Ok(stats.clone());
+            }
+        }
+
+        let stats = self.fetch_stats(channel, window).await?;
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), stats.clone()));
 
         Ok(stats)
     }
+
+    async fn fetch_stats(
+        &self,
+        channel: &str,
+        window: StatsWindow,
+    ) -> Result<ChatStats<'static>, Error> {
+        let mut url = self.url(&format!("chatstats/{}/stats", channel));
+        if let Some(interval) = window.interval() {
+            url = format!("{}?interval={}", url, interval);
+        }
+
+        self.get("stats", &url).await
+    }
+}
+
+/// The settings every client shares.
+///
+/// Gzip and brotli make reqwest send `Accept-Encoding` and decode the
+/// stats transparently, which shrinks them a lot.
+fn client_builder(timeout: Duration) -> reqwest::ClientBuilder {
+    reqwest::ClientBuilder::new()
+        .timeout(timeout)
+        .gzip(true)
+        .brotli(true)
+}
+
+/// Parse `Retry-After`, given either in seconds or as a date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(value).ok()?;
+            // a date in the past means we may retry right away
+            Some(
+                (date.with_timezone(&Utc) - Utc::now())
+                    .to_std()
+                    .unwrap_or_default(),
+            )
+        }
+    }
+}
+
+fn ratelimit_remaining(headers: &HeaderMap) -> Option<f64> {
+    headers
+        .get("x-ratelimit-remaining")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Collapse a status code into its class to keep label cardinality low.
+fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{ApiClient, Error};
+    use super::{
+        mock::MockApi, retry_after, status_class, ApiClient, ChatStats, Emote, EmoteType, Error,
+        StatsWindow, ValidationError, DEFAULT_USER_AGENT, MAX_ENTRIES,
+    };
+    use reqwest::{
+        header::{HeaderMap, HeaderValue, RETRY_AFTER},
+        Proxy, StatusCode, Url,
+    };
+    use std::time::Duration;
+    use tokio::time;
+
+    const GLOBAL_STATS: &str = include_str!("../../tests/fixtures/global_stats.json");
+    const CORRUPT_STATS: &str = include_str!("../../tests/fixtures/global_stats_corrupt.json");
 
     #[tokio::test]
     async fn get_top_channels() -> Result<(), Error> {
-        let client = ApiClient::new()?;
+        let api = MockApi::start();
+        let client = ApiClient::new()?.with_base_url(api.url());
         let channels = client.get_top_channels().await?;
 
-        assert_eq!(channels.len(), 100);
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[0].channel, "xqcow");
+        assert_eq!(channels[0].messages, 1166069389);
 
         Ok(())
     }
 
     #[tokio::test]
     async fn get_global_stats() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?.with_base_url(api.url());
+        let stats = client.get_stats("global").await?;
+
+        assert_eq!(stats.channel, "global");
+        assert_eq!(stats.total_messages, 67397996744);
+        assert_eq!(stats.commands.len(), 2);
+        assert_eq!(stats.hashtags.len(), 2);
+        assert_eq!(stats.bttv_emotes.len(), 2);
+        assert_eq!(stats.ffz_emotes.len(), 2);
+        assert_eq!(stats.twitch_emotes.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn healthcheck_fetches_one_channel() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?.with_base_url(api.url());
+
+        client.healthcheck().await?;
+
+        assert_eq!(api.requests(), ["/chatstats"]);
+        assert_eq!(api.queries(), [Some(String::from("limit=1"))]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn healthcheck_fails_when_the_api_is_down() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?
+            .with_base_url(api.url().join("down/").unwrap())
+            .with_retries(0, Duration::from_millis(1));
+
+        match client.healthcheck().await {
+            Err(Error::HttpStatus { status, .. }) => {
+                assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    /// Run with `cargo test -- --ignored` to check the real api still looks the same.
+    #[tokio::test]
+    #[ignore]
+    async fn live_api() -> Result<(), Error> {
         let client = ApiClient::new()?;
+
+        assert!(!client.get_top_channels().await?.is_empty());
+
+        let stats = client.get_stats("global").await?;
+        assert_eq!(stats.channel, "global");
+        assert!(stats.total_messages > 0);
+        assert!(!stats.twitch_emotes.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_stats_from_base_url() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?.with_base_url(api.url());
         let stats = client.get_stats("global").await?;
 
         assert_eq!(stats.channel, "global");
-        assert!(stats.total_messages > 67397996744);
-        assert_eq!(stats.commands.len(), 100);
-        assert_eq!(stats.hashtags.len(), 100);
-        assert_eq!(stats.bttv_emotes.len(), 100);
-        assert_eq!(stats.ffz_emotes.len(), 100);
-        assert_eq!(stats.twitch_emotes.len(), 100);
+        assert_eq!(api.requests(), ["/chatstats/global/stats"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requests_go_through_the_proxy() -> Result<(), Error> {
+        let proxy = MockApi::start();
+        // only the proxy can answer for this host
+        let client = ApiClient::new()?
+            .with_base_url(Url::parse("http://api.invalid/").unwrap())
+            .with_proxy(Proxy::http(proxy.url()).unwrap())?;
+
+        client.get_top_channels().await?;
+        assert_eq!(proxy.requests(), ["/chatstats"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gzip_responses_are_decoded() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?.with_base_url(api.url());
+        let stats = client.get_stats("gzipped").await?;
+
+        assert_eq!(stats.total_messages, 67397996744);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn windows_are_sent_as_interval() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?.with_base_url(api.url());
+
+        client.get_stats("global").await?;
+        client
+            .get_windowed_stats("global", StatsWindow::Last7d)
+            .await?;
+
+        assert_eq!(api.queries(), [None, Some(String::from("interval=7d"))]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cache_answers_within_ttl() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?
+            .with_base_url(api.url())
+            .with_cache_ttl(Duration::from_secs(60));
+
+        client.get_stats("global").await?;
+        client.get_stats("global").await?;
+        client.get_stats("forsen").await?;
+
+        assert_eq!(
+            api.requests(),
+            ["/chatstats/global/stats", "/chatstats/forsen/stats"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sends_user_agent() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?.with_base_url(api.url());
+        client.get_top_channels().await?;
+        let client = client.with_user_agent(String::from("my-exporter/1.0"));
+        client.get_top_channels().await?;
+
+        assert_eq!(
+            api.user_agents(),
+            [
+                Some(String::from(DEFAULT_USER_AGENT)),
+                Some(String::from("my-exporter/1.0"))
+            ]
+        );
+        assert!(DEFAULT_USER_AGENT.starts_with("se-stats-exporter/"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sends_token_only_when_set() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?.with_base_url(api.url());
+        client.get_top_channels().await?;
+        let client = client.with_token(String::from("s3cr3t"));
+        client.get_top_channels().await?;
+
+        assert_eq!(
+            api.authorizations(),
+            [None, Some(String::from("Bearer s3cr3t"))]
+        );
+        assert!(!format!("{:?}", client).contains("s3cr3t"));
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn rate_limited() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?
+            .with_base_url(api.url())
+            .with_retries(0, Duration::from_millis(1));
+
+        match client.get_stats("ratelimited").await {
+            Err(Error::RateLimited { retry_after, .. }) => {
+                assert_eq!(retry_after, Some(Duration::from_secs(1)))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn long_retry_after_is_not_waited_for() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?
+            .with_base_url(api.url())
+            .with_retries(3, Duration::from_millis(1))
+            .with_max_retry_delay(Duration::from_secs(60));
+
+        let result = time::timeout(Duration::from_secs(5), client.get_stats("ratelimited_long"))
+            .await
+            .expect("waited for the retry-after");
+
+        match result {
+            Err(Error::RateLimited { retry_after, .. }) => {
+                assert_eq!(retry_after, Some(Duration::from_secs(3600)))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(api.requests().len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limit_backoff_is_capped() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?
+            .with_base_url(api.url())
+            .with_retries(1, Duration::from_secs(3600))
+            .with_max_retry_delay(Duration::from_millis(1));
+
+        // without a retry-after the backoff applies, and that is far above the cap
+        let result = time::timeout(
+            Duration::from_secs(5),
+            client.get_stats("ratelimited_silent"),
+        )
+        .await
+        .expect("waited for the backoff");
+
+        assert!(matches!(
+            result,
+            Err(Error::RateLimited {
+                retry_after: None,
+                ..
+            })
+        ));
+        assert_eq!(api.requests().len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_errors_are_not_parsed() -> Result<(), Error> {
+        let api = MockApi::start();
+        let client = ApiClient::new()?
+            .with_base_url(api.url())
+            .with_retries(1, Duration::from_millis(1));
+
+        match client.get_stats("unavailable").await {
+            Err(Error::HttpStatus { status, .. }) => {
+                assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(api.requests().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::default()));
+    }
+
+    #[test]
+    fn base_url_keeps_its_path() {
+        let client = ApiClient::new()
+            .unwrap()
+            .with_base_url(Url::parse("http://localhost:8080/kappa/v2").unwrap());
+
+        assert_eq!(
+            client.url("chatstats"),
+            "http://localhost:8080/kappa/v2/chatstats"
+        );
+    }
+
     #[test]
     fn sanity_check_message_count_fits_in_u64() {
         let _: u64 = 67397996744;
     }
+
+    #[test]
+    fn validate_accepts_sane_stats() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+
+        assert!(stats.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_total_below_emote_usage() {
+        let stats: ChatStats = serde_json::from_str(CORRUPT_STATS).unwrap();
+
+        match stats.validate() {
+            Err(ValidationError::TotalBelowEmoteUsage {
+                total_messages,
+                emote_usage,
+            }) => {
+                assert_eq!(total_messages, 1000);
+                assert_eq!(emote_usage, 349293177 + 1259762743);
+            }
+            other => panic!("unexpected validation result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn validate_rejects_oversized_lists() {
+        let mut stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+        let chatter = stats.chatters[0].clone();
+        stats.chatters = vec![chatter; MAX_ENTRIES + 1].into();
+
+        assert!(matches!(
+            stats.validate(),
+            Err(ValidationError::TooManyEntries {
+                field: "chatters",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn serialize_uses_api_field_names() {
+        let stats: ChatStats = serde_json::from_str(GLOBAL_STATS).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&stats).unwrap(),
+            serde_json::from_str::<serde_json::Value>(GLOBAL_STATS).unwrap()
+        );
+    }
+
+    #[test]
+    fn missing_and_null_lists_are_empty() {
+        let stats: ChatStats =
+            serde_json::from_str(r#"{"channel": "tiny", "totalMessages": 3, "commands": null}"#)
+                .unwrap();
+
+        assert_eq!(stats.total_messages, 3);
+        assert!(stats.chatters.is_empty());
+        assert!(stats.commands.is_empty());
+        assert!(stats.twitch_emotes.is_empty());
+        assert!(stats.validate().is_ok());
+    }
+
+    #[test]
+    fn parse_emote_type() {
+        let emote: Emote = serde_json::from_str(
+            r#"{
+                "name": "LUL",
+                "_id": "55028cd2135896936880fdd7",
+                "type": "bttv",
+                "width": 28,
+                "height": 28,
+                "gif": false
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(emote.typ, EmoteType::BTTV);
+    }
+
+    #[test]
+    fn status_classes() {
+        assert_eq!(status_class(StatusCode::OK), "2xx");
+        assert_eq!(status_class(StatusCode::NOT_MODIFIED), "3xx");
+        assert_eq!(status_class(StatusCode::TOO_MANY_REQUESTS), "4xx");
+        assert_eq!(status_class(StatusCode::SERVICE_UNAVAILABLE), "5xx");
+    }
 }
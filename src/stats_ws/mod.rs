@@ -0,0 +1,1037 @@
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Notify,
+    },
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, info, warn};
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub const URL: &str = "wss://twitchstats-ws.streamelements.com/";
+
+/// The channel whose room carries the changes of every channel, like `get_stats("global")`.
+pub const GLOBAL: &str = "global";
+
+/// How many commands can be queued before `subscribe_to_stats` waits.
+pub const DEFAULT_OUTGOING_CAPACITY: usize = 32;
+
+/// How many unread messages are buffered before the oldest ones get dropped.
+pub const DEFAULT_INCOMING_CAPACITY: usize = 1024;
+
+/// The delay before the first reconnect, doubled on every further attempt.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// How long a connection has to stay up before the reconnect delay starts over.
+const STABLE_SESSION: Duration = Duration::from_secs(30);
+
+/// How often an idle connection gets pinged so the server keeps it open.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Could not connect to {url}")]
+    ConnectError {
+        url: String,
+        source: tokio_tungstenite::tungstenite::Error,
+    },
+
+    #[error("Could not read from websocket")]
+    ReadMessageError(#[source] tokio_tungstenite::tungstenite::Error),
+
+    #[error("Could not write to websocket")]
+    WriteMessageError(#[source] tokio_tungstenite::tungstenite::Error),
+
+    #[error("Websocket connection closed")]
+    ConnectionClosedError,
+
+    #[error("Could not serialize command")]
+    SerializeCommandError(#[source] serde_json::Error),
+
+    #[error("Could not send command, the websocket is closed")]
+    SendCommandError,
+
+    #[error("Could not receive message, the websocket is closed")]
+    RecvIncomingMessageError,
+
+    #[error("Could not parse message")]
+    ParseMessageError(#[source] serde_json::Error),
+
+    #[error("Message for {0:?}, which is not a stats room")]
+    UnknownRoomError(String),
+
+    #[error("Websocket task panicked")]
+    JoinError(#[source] tokio::task::JoinError),
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Command<'a> {
+    command: &'a str,
+    data: CommandData,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct CommandData {
+    room: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RawStatsMessage<'a> {
+    pub id: Cow<'a, str>,
+    pub event: Cow<'a, str>,
+    pub destination: Cow<'a, str>,
+    pub data: StatsData<'a>,
+}
+
+impl<'a> RawStatsMessage<'a> {
+    /// All changes in this message, with batches flattened.
+    pub fn into_changes(self) -> Vec<StatsChangeMessage<'a>> {
+        match self.data {
+            StatsData::Single(changes) => changes,
+            StatsData::Batch(batches) => batches.into_iter().flatten().collect(),
+        }
+    }
+}
+
+/// `message` events carry a list of changes, `batch` events a list of such lists.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum StatsData<'a> {
+    Single(Vec<StatsChangeMessage<'a>>),
+    Batch(Vec<Vec<StatsChangeMessage<'a>>>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StatsChangeMessage<'a> {
+    Chatters {
+        key: Cow<'a, str>,
+        amount: u64,
+    },
+    Emotes {
+        key: Cow<'a, str>,
+        amount: u64,
+    },
+    Hashtags {
+        key: Cow<'a, str>,
+        amount: u64,
+    },
+    Commands {
+        key: Cow<'a, str>,
+        amount: u64,
+    },
+    /// Any change type this client does not know yet.
+    #[serde(other)]
+    Unknown,
+}
+
+impl StatsChangeMessage<'_> {
+    /// The `type` the change was sent with, `unknown` for types this client does not know.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            StatsChangeMessage::Chatters { .. } => "chatters",
+            StatsChangeMessage::Emotes { .. } => "emotes",
+            StatsChangeMessage::Hashtags { .. } => "hashtags",
+            StatsChangeMessage::Commands { .. } => "commands",
+            StatsChangeMessage::Unknown => "unknown",
+        }
+    }
+}
+
+/// A connection to the twitchstats websocket.
+///
+/// The socket is driven by a background task; commands and messages are passed
+/// to it through channels. When the connection drops the task reconnects with
+/// exponential backoff and subscribes to every room again.
+///
+/// The socket is always read, so pings get answered even when messages are not
+/// received fast enough. A message that arrives while the buffer is full pushes out
+/// the oldest unread one, which is counted in [`WsClient::dropped_messages`]. Every
+/// message carries the latest changes, so the newest are the ones worth keeping.
+#[derive(Debug)]
+pub struct WsClient {
+    outgoing: Sender<Request>,
+    incoming: Arc<Inbox>,
+    connected: Arc<AtomicBool>,
+    dropped: Arc<AtomicU64>,
+    handle: JoinHandle<Result<(), Error>>,
+}
+
+/// Commands for the background task.
+#[derive(Debug)]
+enum Request {
+    Subscribe(String),
+    Unsubscribe(String),
+    Close,
+}
+
+impl WsClient {
+    pub async fn new() -> Result<Self, Error> {
+        Self::connect(URL).await
+    }
+
+    /// Like [`WsClient::new`], but with other channel sizes than
+    /// [`DEFAULT_OUTGOING_CAPACITY`] and [`DEFAULT_INCOMING_CAPACITY`].
+    ///
+    /// `incoming` is how far [`WsClient::recv_message`] may fall behind before the oldest
+    /// messages are dropped, so raise it for busy feeds like `global`. `outgoing` only limits how
+    /// many subscriptions can be queued before they wait. Values below 1 count as 1.
+    pub async fn with_capacities(outgoing: usize, incoming: usize) -> Result<Self, Error> {
+        Self::connect_with_capacities(URL, outgoing, incoming).await
+    }
+
+    /// Connect to another server speaking the same protocol.
+    pub async fn connect(url: &str) -> Result<Self, Error> {
+        Self::connect_with_capacities(url, DEFAULT_OUTGOING_CAPACITY, DEFAULT_INCOMING_CAPACITY)
+            .await
+    }
+
+    /// Connect to `url` with the channel sizes of [`WsClient::with_capacities`].
+    pub async fn connect_with_capacities(
+        url: &str,
+        outgoing: usize,
+        incoming: usize,
+    ) -> Result<Self, Error> {
+        let (ws, _) = connect_async(url)
+            .await
+            .map_err(|source| Error::ConnectError {
+                url: url.to_string(),
+                source,
+            })?;
+
+        let (outgoing, outgoing_receiver) = mpsc::channel(outgoing.max(1));
+        let incoming = Arc::new(Inbox::new(incoming.max(1)));
+        let connected = Arc::new(AtomicBool::new(true));
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let handle = tokio::spawn(run(
+            url.to_string(),
+            ws,
+            outgoing_receiver,
+            Arc::clone(&incoming),
+            Arc::clone(&connected),
+            Arc::clone(&dropped),
+        ));
+
+        Ok(Self {
+            outgoing,
+            incoming,
+            connected,
+            dropped,
+            handle,
+        })
+    }
+
+    /// Whether the socket is connected right now, false while reconnecting.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// How many messages were dropped because they were not received fast enough.
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Subscribe to the stats room of `channel`, e.g. `global`.
+    ///
+    /// One client can be subscribed to several rooms. Subscriptions are renewed
+    /// after every reconnect.
+    pub async fn subscribe_to_stats(&self, channel: &str) -> Result<(), Error> {
+        self.send(Request::Subscribe(stats_room(channel))).await
+    }
+
+    /// Subscribe to the [`GLOBAL`] room, which mostly sends `batch` messages.
+    pub async fn subscribe_global(&self) -> Result<(), Error> {
+        self.subscribe_to_stats(GLOBAL).await
+    }
+
+    /// Stop receiving stats for `channel`.
+    pub async fn unsubscribe(&self, channel: &str) -> Result<(), Error> {
+        self.send(Request::Unsubscribe(stats_room(channel))).await
+    }
+
+    async fn send(&self, request: Request) -> Result<(), Error> {
+        self.outgoing
+            .send(request)
+            .await
+            .map_err(|_| Error::SendCommandError)
+    }
+
+    /// Wait for the next message and return its channel and the changes it contains.
+    ///
+    /// A message that cannot be parsed returns [`Error::ParseMessageError`], one for
+    /// another room [`Error::UnknownRoomError`]. The next call goes on with the
+    /// message after it.
+    pub async fn recv_message(
+        &mut self,
+    ) -> Result<(String, Vec<StatsChangeMessage<'static>>), Error> {
+        let text = self
+            .incoming
+            .recv()
+            .await
+            .ok_or(Error::RecvIncomingMessageError)?;
+        let message: RawStatsMessage =
+            serde_json::from_str(&text).map_err(Error::ParseMessageError)?;
+        let channel = parse_room(&message.destination)
+            .ok_or_else(|| Error::UnknownRoomError(message.destination.to_string()))?
+            .to_string();
+
+        Ok((channel, message.into_changes()))
+    }
+
+    /// Wait for the background task to finish.
+    pub async fn join(self) -> Result<(), Error> {
+        self.handle.await.map_err(Error::JoinError)?
+    }
+
+    /// Send a close frame, stop reading and wait for the background task.
+    ///
+    /// Closing a client whose connection is already gone is fine, there just is
+    /// no close frame to send.
+    pub async fn close(self) -> Result<(), Error> {
+        // fails if the task is gone already, join tells us why
+        let _ = self.outgoing.send(Request::Close).await;
+
+        self.handle.await.map_err(Error::JoinError)?
+    }
+}
+
+/// Messages read from the socket that were not received yet.
+///
+/// Unlike a channel it makes room for a new message by dropping the oldest one.
+#[derive(Debug)]
+struct Inbox {
+    messages: Mutex<VecDeque<String>>,
+    capacity: usize,
+    closed: AtomicBool,
+    ready: Notify,
+}
+
+impl Inbox {
+    fn new(capacity: usize) -> Self {
+        Inbox {
+            messages: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            closed: AtomicBool::new(false),
+            ready: Notify::new(),
+        }
+    }
+
+    /// Queue `message`, true if the oldest message was dropped for it.
+    fn push(&self, message: String) -> bool {
+        let full = {
+            let mut messages = self.messages.lock().unwrap();
+            let full = messages.len() >= self.capacity;
+            if full {
+                messages.pop_front();
+            }
+            messages.push_back(message);
+            full
+        };
+        self.ready.notify_one();
+        full
+    }
+
+    /// Let [`Inbox::recv`] return `None` once the queued messages are received.
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.ready.notify_one();
+    }
+
+    /// Wait for the oldest message, `None` once the inbox is closed and empty.
+    async fn recv(&self) -> Option<String> {
+        loop {
+            if let Some(message) = self.messages.lock().unwrap().pop_front() {
+                return Some(message);
+            }
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            // a push without a waiter leaves a permit, so none is missed
+            self.ready.notified().await;
+        }
+    }
+}
+
+/// Closes the inbox when the background task ends, even by panicking.
+struct CloseOnDrop(Arc<Inbox>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+fn stats_room(channel: &str) -> String {
+    format!("twitchstats:{}:stats", channel)
+}
+
+/// The channel of a stats room like `twitchstats:forsen:stats`, the reverse of subscribing.
+pub fn parse_room(room: &str) -> Option<&str> {
+    room.strip_prefix("twitchstats:")?
+        .strip_suffix(":stats")
+        .filter(|channel| !channel.is_empty() && !channel.contains(':'))
+}
+
+fn command(command: &str, room: &str) -> Result<Message, serde_json::Error> {
+    let command = Command {
+        command,
+        data: CommandData {
+            room: room.to_string(),
+        },
+    };
+
+    serde_json::to_string(&command).map(Message::Text)
+}
+
+/// Keep a connection alive until the client is dropped.
+async fn run(
+    url: String,
+    ws: WebSocket,
+    mut requests: Receiver<Request>,
+    incoming: Arc<Inbox>,
+    connected: Arc<AtomicBool>,
+    dropped: Arc<AtomicU64>,
+) -> Result<(), Error> {
+    let _close = CloseOnDrop(Arc::clone(&incoming));
+    let mut rooms = Vec::new();
+    let mut ws = Some(ws);
+    let mut delay = RECONNECT_DELAY;
+
+    loop {
+        let socket = match ws.take() {
+            Some(socket) => Some(socket),
+            None => match connect_async(url.as_str()).await {
+                Ok((socket, _)) => {
+                    info!("Reconnected to {}", url);
+                    Some(socket)
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not reconnect to {}, retrying in {:?}: {}",
+                        url, delay, e
+                    );
+                    None
+                }
+            },
+        };
+
+        if let Some(socket) = socket {
+            let started = Instant::now();
+            connected.store(true, Ordering::Relaxed);
+            let result = session(socket, &mut rooms, &mut requests, &incoming, &dropped).await;
+            connected.store(false, Ordering::Relaxed);
+
+            let e = match result {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            // a server that closes every connection right away is not retried right away
+            if started.elapsed() >= STABLE_SESSION {
+                delay = RECONNECT_DELAY;
+            }
+            warn!(
+                "Lost connection to {}, reconnecting in {:?}: {}",
+                url, delay, e
+            );
+        }
+
+        if !back_off(delay, &mut rooms, &mut requests).await {
+            return Ok(());
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Wait `delay` before reconnecting, false once the client was closed or dropped.
+///
+/// Subscriptions made in the meantime are sent after reconnecting.
+async fn back_off(
+    delay: Duration,
+    rooms: &mut Vec<String>,
+    requests: &mut Receiver<Request>,
+) -> bool {
+    let sleep = time::sleep(delay);
+    tokio::pin!(sleep);
+
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return true,
+            request = requests.recv() => match request {
+                Some(Request::Subscribe(room)) => {
+                    if !rooms.contains(&room) {
+                        rooms.push(room);
+                    }
+                }
+                Some(Request::Unsubscribe(room)) => rooms.retain(|r| *r != room),
+                // the client was closed or dropped
+                Some(Request::Close) | None => return false,
+            },
+        }
+    }
+}
+
+/// Forward messages over one connection.
+///
+/// Returns `Ok` once the client was closed or dropped and an error when the connection is lost.
+async fn session(
+    socket: WebSocket,
+    rooms: &mut Vec<String>,
+    requests: &mut Receiver<Request>,
+    incoming: &Inbox,
+    dropped: &AtomicU64,
+) -> Result<(), Error> {
+    let (mut sink, mut stream) = socket.split();
+
+    for room in rooms.iter() {
+        sink.send(command("subscribe", room).map_err(Error::SerializeCommandError)?)
+            .await
+            .map_err(Error::WriteMessageError)?;
+    }
+
+    let mut ping = time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = ping.tick() => {
+                sink.send(Message::Ping(Vec::new()))
+                    .await
+                    .map_err(Error::WriteMessageError)?;
+            }
+            request = requests.recv() => match request {
+                // update the rooms first, a send that fails is caught up on after reconnecting
+                Some(Request::Subscribe(room)) => {
+                    let message = command("subscribe", &room).map_err(Error::SerializeCommandError)?;
+                    if !rooms.contains(&room) {
+                        rooms.push(room);
+                    }
+                    sink.send(message).await.map_err(Error::WriteMessageError)?;
+                }
+                Some(Request::Unsubscribe(room)) => {
+                    let message = command("unsubscribe", &room).map_err(Error::SerializeCommandError)?;
+                    rooms.retain(|r| *r != room);
+                    sink.send(message).await.map_err(Error::WriteMessageError)?;
+                }
+                Some(Request::Close) => {
+                    // the connection might be half dead, there is nothing left to do either way
+                    if let Err(e) = sink.send(Message::Close(None)).await {
+                        debug!("Could not send close frame: {}", e);
+                    }
+                    return Ok(());
+                }
+                // the client was dropped
+                None => return Ok(()),
+            },
+            message = stream.next() => match message {
+                // waiting for room would stop us from answering pings
+                Some(Ok(Message::Text(text))) => {
+                    if incoming.push(text) {
+                        let count = dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                        // warn on the first drop, then less and less often
+                        if count.is_power_of_two() {
+                            warn!("Incoming messages are not read fast enough, dropped {} so far", count);
+                        }
+                    }
+                }
+                // answer right away instead of waiting for the next write
+                Some(Ok(Message::Ping(data))) => {
+                    sink.send(Message::Pong(data))
+                        .await
+                        .map_err(Error::WriteMessageError)?;
+                }
+                // only text frames reach recv_message
+                Some(Ok(message)) => debug!("Ignoring websocket message {:?}", message),
+                Some(Err(e)) => return Err(Error::ReadMessageError(e)),
+                None => return Err(Error::ConnectionClosedError),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_room, session, stats_room, Error, Inbox, RawStatsMessage, Request,
+        StatsChangeMessage, WsClient, DEFAULT_INCOMING_CAPACITY, GLOBAL,
+    };
+    use futures_util::{future, SinkExt, StreamExt};
+    use std::{sync::atomic::AtomicU64, time::Duration};
+    use tokio::{net::TcpListener, sync::mpsc, time};
+    use tokio_tungstenite::{accept_async, connect_async, tungstenite::Message};
+
+    fn parse_change(json: &str) -> StatsChangeMessage<'static> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn parse_stats_message() {
+        let message: RawStatsMessage = serde_json::from_str(
+            r#"{
+                "id": "8dc4c3d2-01ad-4c8b-9d77-c6b1ec4c5e9a",
+                "event": "message",
+                "destination": "twitchstats:global:stats",
+                "data": [
+                    {"type": "chatters", "key": "nightbot", "amount": 2},
+                    {"type": "emotes", "key": "Kappa", "amount": 1}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(message.destination, "twitchstats:global:stats");
+        assert_eq!(
+            message.into_changes(),
+            vec![
+                StatsChangeMessage::Chatters {
+                    key: "nightbot".into(),
+                    amount: 2
+                },
+                StatsChangeMessage::Emotes {
+                    key: "Kappa".into(),
+                    amount: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_batch_message() {
+        let message: RawStatsMessage = serde_json::from_str(
+            r#"{
+                "id": "0b0e3c1f-52c8-4b39-9f33-8f14a0d3f8e2",
+                "event": "batch",
+                "destination": "twitchstats:global:stats",
+                "data": [
+                    [
+                        {"type": "chatters", "key": "nightbot", "amount": 1},
+                        {"type": "emotes", "key": "LUL", "amount": 3}
+                    ],
+                    [
+                        {"type": "chatters", "key": "streamelements", "amount": 1}
+                    ]
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(message.event, "batch");
+        assert_eq!(message.into_changes().len(), 3);
+    }
+
+    #[test]
+    fn parse_hashtag_change() {
+        assert_eq!(
+            parse_change(r##"{"type": "hashtags", "key": "#ad", "amount": 4}"##),
+            StatsChangeMessage::Hashtags {
+                key: "#ad".into(),
+                amount: 4
+            }
+        );
+    }
+
+    #[test]
+    fn parse_command_change() {
+        assert_eq!(
+            parse_change(r#"{"type": "commands", "key": "!uptime", "amount": 1}"#),
+            StatsChangeMessage::Commands {
+                key: "!uptime".into(),
+                amount: 1
+            }
+        );
+    }
+
+    #[test]
+    fn parse_unknown_change() {
+        assert_eq!(
+            parse_change(r#"{"type": "cheers", "key": "nightbot", "amount": 100}"#),
+            StatsChangeMessage::Unknown
+        );
+    }
+
+    #[test]
+    fn change_kinds_match_their_type() {
+        let changes: Vec<StatsChangeMessage> = serde_json::from_str(
+            r#"[
+                {"type": "chatters", "key": "forsen", "amount": 1},
+                {"type": "emotes", "key": "LULW", "amount": 1},
+                {"type": "subs", "key": "forsen", "amount": 1}
+            ]"#,
+        )
+        .unwrap();
+
+        let kinds: Vec<&str> = changes.iter().map(StatsChangeMessage::kind).collect();
+        assert_eq!(kinds, ["chatters", "emotes", "unknown"]);
+    }
+
+    #[test]
+    fn rooms_round_trip() {
+        assert_eq!(parse_room(&stats_room("global")), Some("global"));
+        assert_eq!(parse_room("twitchstats:forsen:stats"), Some("forsen"));
+
+        for room in [
+            "",
+            "global",
+            "twitchstats::stats",
+            "twitchstats:forsen",
+            "forsen:stats",
+            "twitchstats:a:b:stats",
+            "chatstats:forsen:stats",
+        ]
+        .iter()
+        {
+            assert_eq!(parse_room(room), None, "{:?}", room);
+        }
+    }
+
+    #[tokio::test]
+    async fn close_sends_close_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+
+            while let Some(Ok(message)) = ws.next().await {
+                if let Message::Close(_) = message {
+                    return true;
+                }
+            }
+            false
+        });
+
+        let client = WsClient::connect(&url).await.unwrap();
+        client.close().await.unwrap();
+
+        assert!(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn parse_errors_do_not_end_the_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            ws.send(Message::Text(String::from("not json")))
+                .await
+                .unwrap();
+            ws.send(Message::Text(String::from(
+                r#"{"id": "1", "event": "message", "destination": "twitchstats:forsen:stats", "data": []}"#,
+            )))
+            .await
+            .unwrap();
+            while ws.next().await.is_some() {}
+        });
+
+        let mut client = WsClient::connect(&url).await.unwrap();
+
+        assert!(matches!(
+            client.recv_message().await,
+            Err(Error::ParseMessageError(_))
+        ));
+        let (channel, changes) = client.recv_message().await.unwrap();
+        assert_eq!(channel, "forsen");
+        assert!(changes.is_empty());
+
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscribe_global_receives_batches() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            let subscribe = match ws.next().await {
+                Some(Ok(Message::Text(text))) => text,
+                message => panic!("expected a subscribe command, got {:?}", message),
+            };
+            ws.send(Message::Text(String::from(
+                r#"{
+                    "id": "0b0e3c1f-52c8-4b39-9f33-8f14a0d3f8e2",
+                    "event": "batch",
+                    "destination": "twitchstats:global:stats",
+                    "data": [
+                        [
+                            {"type": "chatters", "key": "nightbot", "amount": 1},
+                            {"type": "emotes", "key": "LUL", "amount": 3}
+                        ],
+                        [
+                            {"type": "chatters", "key": "nightbot", "amount": 2}
+                        ]
+                    ]
+                }"#,
+            )))
+            .await
+            .unwrap();
+            while ws.next().await.is_some() {}
+            subscribe
+        });
+
+        let mut client = WsClient::connect(&url).await.unwrap();
+        client.subscribe_global().await.unwrap();
+
+        let (channel, changes) = client.recv_message().await.unwrap();
+        assert_eq!(channel, GLOBAL);
+        let nightbot: u64 = changes
+            .iter()
+            .filter_map(|change| match change {
+                StatsChangeMessage::Chatters { key, amount } if key == "nightbot" => Some(amount),
+                _ => None,
+            })
+            .sum();
+        assert_eq!(nightbot, 3);
+        assert_eq!(changes.len(), 3);
+
+        client.close().await.unwrap();
+        let subscribe: serde_json::Value = serde_json::from_str(&server.await.unwrap()).unwrap();
+        assert_eq!(
+            subscribe,
+            serde_json::json!({"command": "subscribe", "data": {"room": "twitchstats:global:stats"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_subscribe_is_kept_for_the_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // never read, so our close frame is not answered and the read half stays open
+            let _ws = accept_async(stream).await.unwrap();
+            future::pending::<()>().await;
+        });
+
+        let (mut socket, _) = connect_async(url.as_str()).await.unwrap();
+        // a closing socket refuses every further write
+        socket.close(None).await.unwrap();
+        let (requests_sender, mut requests) = mpsc::channel(1);
+        let incoming = Inbox::new(1);
+        requests_sender
+            .send(Request::Subscribe(stats_room("forsen")))
+            .await
+            .unwrap();
+        let mut rooms = Vec::new();
+
+        let result = session(
+            socket,
+            &mut rooms,
+            &mut requests,
+            &incoming,
+            &AtomicU64::new(0),
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::WriteMessageError(_))));
+        assert_eq!(rooms, [stats_room("forsen")]);
+    }
+
+    #[tokio::test]
+    async fn rooms_are_subscribed_again_after_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let mut subscriptions = Vec::new();
+            // drop the connection after the subscription, then wait for the reconnect
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = accept_async(stream).await.unwrap();
+                match ws.next().await {
+                    Some(Ok(Message::Text(text))) => subscriptions.push(text),
+                    message => panic!("expected a subscribe command, got {:?}", message),
+                }
+            }
+            subscriptions
+        });
+
+        let client = WsClient::connect(&url).await.unwrap();
+        client.subscribe_to_stats("forsen").await.unwrap();
+        let subscriptions = time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+        client.close().await.unwrap();
+
+        assert_eq!(subscriptions[0], subscriptions[1]);
+        assert!(subscriptions[1].contains("twitchstats:forsen:stats"));
+    }
+
+    #[tokio::test]
+    async fn reconnects_to_a_closing_server_are_spaced_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            // the first connection and two reconnects, each dropped right after the handshake
+            for _ in 0..3 {
+                let (stream, _) = listener.accept().await.unwrap();
+                drop(accept_async(stream).await.unwrap());
+                accepted.push(time::Instant::now());
+            }
+            accepted
+        });
+
+        let client = WsClient::connect(&url).await.unwrap();
+        let accepted = time::timeout(Duration::from_secs(10), server)
+            .await
+            .unwrap()
+            .unwrap();
+        client.close().await.unwrap();
+
+        assert!(accepted[1] - accepted[0] >= Duration::from_millis(900));
+        assert!(accepted[2] - accepted[1] >= Duration::from_millis(1900));
+    }
+
+    #[tokio::test]
+    async fn full_buffer_drops_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let extra = 10;
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            for _ in 0..DEFAULT_INCOMING_CAPACITY + extra {
+                ws.send(Message::Text(String::from("{}"))).await.unwrap();
+            }
+            // keep the connection open
+            while ws.next().await.is_some() {}
+        });
+
+        let client = WsClient::connect(&url).await.unwrap();
+        time::timeout(Duration::from_secs(5), async {
+            while client.dropped_messages() < extra as u64 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(client.dropped_messages(), extra as u64);
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tiny_capacities_drop_early() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            for _ in 0..12 {
+                ws.send(Message::Text(String::from("{}"))).await.unwrap();
+            }
+            while ws.next().await.is_some() {}
+        });
+
+        let mut client = WsClient::connect_with_capacities(&url, 1, 2).await.unwrap();
+        time::timeout(Duration::from_secs(5), async {
+            while client.dropped_messages() < 10 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(client.dropped_messages(), 10);
+        // the two buffered ones are still there, "{}" just is no stats message
+        for _ in 0..2 {
+            assert!(matches!(
+                client.recv_message().await,
+                Err(Error::ParseMessageError(_))
+            ));
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_buffer_keeps_the_newest_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            for i in 0..5 {
+                let message = serde_json::json!({
+                    "id": i.to_string(),
+                    "event": "message",
+                    "destination": stats_room(&format!("channel{}", i)),
+                    "data": [],
+                });
+                ws.send(Message::Text(message.to_string())).await.unwrap();
+            }
+            while ws.next().await.is_some() {}
+        });
+
+        let mut client = WsClient::connect_with_capacities(&url, 1, 2).await.unwrap();
+        time::timeout(Duration::from_secs(5), async {
+            while client.dropped_messages() < 3 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        for channel in ["channel3", "channel4"].iter() {
+            assert_eq!(client.recv_message().await.unwrap().0, *channel);
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closed_inbox_hands_out_what_is_left() {
+        let inbox = Inbox::new(1);
+
+        assert!(!inbox.push(String::from("old")));
+        assert!(inbox.push(String::from("new")));
+        inbox.close();
+
+        assert_eq!(inbox.recv().await.as_deref(), Some("new"));
+        assert_eq!(inbox.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn close_after_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // drop the connection and the listener so reconnects fail
+            drop(accept_async(stream).await.unwrap());
+        });
+
+        let client = WsClient::connect(&url).await.unwrap();
+        while client.is_connected() {
+            tokio::task::yield_now().await;
+        }
+
+        client.close().await.unwrap();
+    }
+}
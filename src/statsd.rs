@@ -0,0 +1,181 @@
+use metrics::{GaugeValue, Key, KeyData, Recorder, Unit};
+use std::{
+    borrow::Cow,
+    fmt::Write,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+};
+use tracing::debug;
+
+/// A recorder that sends every update to a StatsD server.
+///
+/// Labels become DogStatsD tags, plain StatsD servers ignore them. Use the dot separator
+/// to get the usual dotted StatsD names.
+#[derive(Debug)]
+pub struct StatsdRecorder {
+    socket: UdpSocket,
+}
+
+impl StatsdRecorder {
+    pub fn new<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        let address = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "statsd address did not resolve",
+            )
+        })?;
+        let local: SocketAddr = if address.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(address)?;
+        // a slow aggregator must never stall an export
+        socket.set_nonblocking(true)?;
+
+        Ok(StatsdRecorder { socket })
+    }
+
+    fn send(&self, key: &KeyData, value: &str, kind: &str) {
+        let line = line(key, value, kind);
+
+        // it is udp, dropping updates is expected
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            debug!("Could not send {:?}: {}", line, e);
+        }
+    }
+}
+
+/// Characters that end a name early.
+const RESERVED_IN_NAMES: &[char] = &[':', '|', '@', '#', ',', '\n'];
+
+/// Characters that end a tag early. Tag values may contain `:`, only the first one splits.
+const RESERVED_IN_TAGS: &[char] = &['|', ',', '#', '\n'];
+
+/// Format one update as `name:value|kind|#key:value,...`.
+///
+/// Chatter names, hashtags and emote codes can contain the separators, they are replaced
+/// with `_` like DogStatsD clients do.
+fn line(key: &KeyData, value: &str, kind: &str) -> String {
+    let mut line = format!(
+        "{}:{}|{}",
+        escape(&key.name().to_string(), RESERVED_IN_NAMES),
+        value,
+        kind
+    );
+
+    for (i, label) in key.labels().enumerate() {
+        let separator = if i == 0 { "|#" } else { "," };
+        write!(
+            line,
+            "{}{}:{}",
+            separator,
+            escape(label.key(), RESERVED_IN_NAMES),
+            escape(label.value(), RESERVED_IN_TAGS)
+        )
+        .unwrap();
+    }
+
+    line
+}
+
+fn escape<'a>(text: &'a str, reserved: &[char]) -> Cow<'a, str> {
+    if text.contains(reserved) {
+        Cow::Owned(text.replace(reserved, "_"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+impl Recorder for StatsdRecorder {
+    fn register_counter(&self, _key: Key, _unit: Option<Unit>, _description: Option<&'static str>) {
+    }
+
+    fn register_gauge(&self, _key: Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn register_histogram(
+        &self,
+        _key: Key,
+        _unit: Option<Unit>,
+        _description: Option<&'static str>,
+    ) {
+    }
+
+    fn increment_counter(&self, key: Key, value: u64) {
+        self.send(&key, &value.to_string(), "c");
+    }
+
+    fn update_gauge(&self, key: Key, value: GaugeValue) {
+        let value = match value {
+            GaugeValue::Absolute(value) => value.to_string(),
+            GaugeValue::Increment(value) => format!("+{}", value),
+            GaugeValue::Decrement(value) => format!("-{}", value),
+        };
+        self.send(&key, &value, "g");
+    }
+
+    fn record_histogram(&self, key: Key, value: f64) {
+        self.send(&key, &value.to_string(), "h");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{line, StatsdRecorder};
+    use metrics::{GaugeValue, Key, KeyData, Label, Recorder};
+    use std::{net::UdpSocket, time::Duration};
+
+    #[test]
+    fn sends_dogstatsd_lines() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let recorder = StatsdRecorder::new(server.local_addr().unwrap()).unwrap();
+        let mut buf = [0; 512];
+        let mut recv = || {
+            let len = server.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        };
+
+        recorder.update_gauge(
+            Key::Owned(KeyData::from_parts(
+                "sestats.emote",
+                vec![
+                    Label::new("provider", "ffz"),
+                    Label::new("emote", "OMEGALUL"),
+                ],
+            )),
+            GaugeValue::Absolute(42.0),
+        );
+        assert_eq!(recv(), "sestats.emote:42|g|#provider:ffz,emote:OMEGALUL");
+
+        recorder.increment_counter(
+            Key::Owned(KeyData::from_name("sestats.api_requests_total")),
+            2,
+        );
+        assert_eq!(recv(), "sestats.api_requests_total:2|c");
+    }
+
+    #[test]
+    fn reserved_characters_are_replaced() {
+        let key = KeyData::from_parts(
+            "sestats.hashtag",
+            vec![
+                Label::new("hashtag", "#ad|sponsored,lul"),
+                Label::new("emote", "D:"),
+            ],
+        );
+
+        assert_eq!(
+            line(&key, "1", "g"),
+            "sestats.hashtag:1|g|#hashtag:_ad_sponsored_lul,emote:D:"
+        );
+        assert_eq!(
+            line(&KeyData::from_name("sestats:up@1"), "1", "g"),
+            "sestats_up_1:1|g"
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use std::{fs, io, path::Path};
+
+/// Replace the file at `path` with `contents` without readers ever seeing a partial file.
+///
+/// The temporary file lives next to `path` so the rename stays on one filesystem.
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "textfile path has no name"))?;
+    // node_exporter only reads *.prom, so it skips the temporary file
+    let temp = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_atomic;
+    use std::{env, fs, process};
+
+    #[test]
+    fn replaces_the_file() {
+        let dir = env::temp_dir().join(format!("se-stats-exporter-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sestats.prom");
+
+        write_atomic(&path, "sestats_up 0\n").unwrap();
+        write_atomic(&path, "sestats_up 1\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "sestats_up 1\n");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}